@@ -0,0 +1,70 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+
+    // honor reproducible builds if asked
+    let build_secs = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse::<u64>().unwrap_or(0),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+
+    // nvidia-smi (or NVML with the nvml feature) and rocm-smi are supported
+    let nvidia = if std::env::var_os("CARGO_FEATURE_NVML").is_some() {
+        "nvidia (nvml)"
+    } else {
+        "nvidia"
+    };
+    let gpu_vendors = [nvidia, "amd (rocm-smi)"];
+
+    println!("cargo:rustc-env=CIRNO_GIT_HASH={}", git_hash);
+    println!(
+        "cargo:rustc-env=CIRNO_BUILD_TIME={}",
+        format_utc(build_secs)
+    );
+    println!(
+        "cargo:rustc-env=CIRNO_GPU_VENDORS={}",
+        gpu_vendors.join(",")
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+// format unix seconds as `YYYY-MM-DDTHH:MM:SSZ`
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
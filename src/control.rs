@@ -0,0 +1,193 @@
+use std::io::{BufRead, BufReader, ErrorKind, Result, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+// a client has this long to send its lines, so a stuck one can not hold the tick
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// a unix socket taking commands like `workers=4`, a line each, every line is
+// answered with a line, connections are only served between ticks
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    // of the socket file, tells it was removed or replaced
+    ino: u64,
+    // recreating a removed socket file failed, it is tried again each poll quietly
+    rebind_failed: bool,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> Result<Self> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    "file exists and is not a socket",
+                ));
+            }
+            // a socket nobody listens on is left by a cirno which did not exit cleanly
+            if UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(
+                    ErrorKind::AddrInUse,
+                    "another cirno is listening on it",
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let ino = std::fs::metadata(path)?.ino();
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            listener,
+            ino,
+            rebind_failed: false,
+        })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    // serve every pending connection, `handle` gets each line and returns the answer
+    pub fn poll(&mut self, mut handle: impl FnMut(&str) -> String) {
+        self.check_socket_file();
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept on control socket: {}", e);
+                    break;
+                }
+            };
+            if let Err(e) = serve(stream, &mut handle) {
+                warn!("Failed to serve control connection: {}", e);
+            }
+        }
+    }
+
+    // a removed socket file can not be connected to any more, bind a new one
+    fn check_socket_file(&mut self) {
+        match std::fs::metadata(&self.path) {
+            Ok(metadata) if metadata.ino() == self.ino => return,
+            _ => {}
+        }
+        if !self.rebind_failed {
+            warn!(
+                "Control socket {} was removed, recreate it",
+                self.path.display()
+            );
+        }
+        match ControlSocket::bind(&self.path) {
+            Ok(control) => {
+                if self.rebind_failed {
+                    info!("Recreated control socket {}", self.path.display());
+                }
+                *self = control;
+            }
+            Err(e) if self.rebind_failed => {
+                debug!(
+                    "Failed to recreate control socket {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to recreate control socket {}: {}, keep trying",
+                    self.path.display(),
+                    e
+                );
+                self.rebind_failed = true;
+            }
+        }
+    }
+}
+
+fn serve(stream: UnixStream, handle: &mut impl FnMut(&str) -> String) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            // the client went quiet without closing, it had its chance
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        info!("Control command: {}", line);
+        writeln!(writer, "{}", handle(line))?;
+    }
+    Ok(())
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        // only remove the socket file if it is still ours
+        if std::fs::metadata(&self.path).is_ok_and(|metadata| metadata.ino() == self.ino) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// what `cirno ctl` does, send `commands` a line each and collect the answers
+pub fn send_commands(path: &Path, commands: &[String]) -> Result<Vec<String>> {
+    let mut stream = UnixStream::connect(path)?;
+    for command in commands {
+        writeln!(stream, "{}", command)?;
+    }
+    stream.shutdown(std::net::Shutdown::Write)?;
+    BufReader::new(stream).lines().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebind_after_failure() {
+        let path = std::env::temp_dir().join(format!("cirno-ctl-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut control = ControlSocket::bind(&path).unwrap();
+        let answer = |control: &mut ControlSocket| {
+            let client = std::thread::spawn({
+                let path = path.clone();
+                move || send_commands(&path, &["status".to_string()])
+            });
+            // the client may not have connected on the first poll
+            for _ in 0..50 {
+                control.poll(|line| format!("ok {}", line));
+                if client.is_finished() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            client.join().unwrap().unwrap()
+        };
+        assert_eq!(answer(&mut control), ["ok status"]);
+
+        // a plain file in the way keeps failing, once noted it is only retried
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        for _ in 0..3 {
+            control.poll(|_| unreachable!());
+            assert!(control.rebind_failed);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        control.poll(|_| unreachable!());
+        assert!(!control.rebind_failed);
+        assert_eq!(answer(&mut control), ["ok status"]);
+        drop(control);
+        assert!(!path.exists());
+    }
+}
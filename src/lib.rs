@@ -1,4 +1,5 @@
 pub mod utils;
 pub mod monitor;
 pub mod task;
-pub mod scheduler;
\ No newline at end of file
+pub mod scheduler;
+pub mod control;
\ No newline at end of file
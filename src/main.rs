@@ -1,21 +1,268 @@
-use cirno::task::gen_tasks_from_file;
-use cirno::{scheduler::Scheduler, utils::cli::Args};
-use clap::Parser;
+use cirno::control::send_commands;
+use cirno::monitor::Monitor;
+use cirno::scheduler::{BatchResult, Scheduler};
+use cirno::task::Task;
+use cirno::task::{
+    apply_task_meta, dedup_tasks, filter_tasks, gen_tasks_from_file, gen_tasks_from_null_file,
+    shuffle_tasks, InputWatcher, TaskNaming,
+};
+use cirno::utils::cli::{Args, BenchArgs, CtlArgs, InputFormat};
+use cirno::utils::clock::unix_time;
+use cirno::utils::process::Process;
+use cirno::utils::unit::format_mem_size;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use log::{error, info, warn};
+use std::collections::HashMap;
 use std::path::Path;
 
+fn print_config(args: &Args) {
+    print!("{}", args.to_toml());
+
+    // derived values are emitted as comments, so the output can be fed back in
+    let monitor = Monitor::new(args);
+    println!();
+    println!("# derived values");
+    println!("# high_mem_thres_bytes = {}", monitor.get_high_mem_thres());
+    println!("# low_mem_thres_bytes = {}", monitor.get_low_mem_thres());
+    println!("# per_task_mem_bytes = {}", monitor.get_per_task_mem());
+    println!(
+        "# gpu_mem_thres_clamped = {:?}",
+        monitor.get_gpu_mem_thres()
+    );
+    println!("# tick_time_ms = {}", (1000.0 / args.tick_rate) as u128);
+}
+
+fn log_summary(result: &BatchResult) {
+    info!(
+        "{} tasks: {} succeeded, {} failed, {} timed out, {} killed, {} skipped, {} unfinished",
+        result.total(),
+        result.succeeded.len(),
+        result.failed.len(),
+        result.timed_out.len(),
+        result.killed.len(),
+        result.skipped.len(),
+        result.unfinished.len()
+    );
+    info!(
+        "Batch took {:.1}s, tasks ran {:.1}s on {} cores, utilization {:.1}%",
+        result.wall_time.as_secs_f64(),
+        result.task_time.as_secs_f64(),
+        result.cores,
+        result.utilization() * 100.0
+    );
+}
+
+// run generated sleep tasks through a real scheduler and report its overhead
+fn bench(bench_args: BenchArgs) {
+    if !(bench_args.duration.is_finite() && bench_args.duration >= 0.0) {
+        BenchArgs::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--duration must not be negative",
+            )
+            .exit();
+    }
+    // the task list is generated, the placeholder only shows up in cirno_run.json
+    let argv = std::iter::once("cirno".to_string())
+        .chain(bench_args.cirno_args)
+        .chain(std::iter::once("<bench>".to_string()));
+    let cli_args = Args::parse_with_config(argv).unwrap_or_else(|e| e.exit());
+    if let Err(e) = cli_args.validate() {
+        Args::command().error(ErrorKind::ValueValidation, e).exit();
+    }
+
+    let mut scheduler = Scheduler::new(&cli_args);
+    let duration = [bench_args.duration.to_string()];
+    for _ in 0..bench_args.tasks {
+        scheduler.submit(Task::with_args("sleep", &duration, None));
+    }
+    let _ = signal_hook::flag::register(signal_hook::consts::SIGINT, scheduler.get_stop_flag_ref());
+    let result = scheduler.start();
+    scheduler.write_report();
+    log_summary(&result);
+
+    let finished: Vec<_> = result
+        .succeeded
+        .iter()
+        .chain(result.failed.iter())
+        .chain(result.timed_out.iter())
+        .chain(result.killed.iter())
+        .collect();
+    let wall_time = result.wall_time.as_secs_f64();
+    let throughput = finished.len() as f64 / wall_time.max(f64::EPSILON);
+    let avg_queued = finished
+        .iter()
+        .map(|task| task.queued_time.as_secs_f64())
+        .sum::<f64>()
+        / finished.len().max(1) as f64;
+    // every worker busy all the time, back to back
+    let rounds = bench_args.tasks.div_ceil(cli_args.workers);
+    let ideal_time = rounds as f64 * bench_args.duration;
+    let (cpu_time, mem) = match Process::new(rustix::process::getpid()) {
+        Ok(process) => (process.cpu_time(false).as_secs_f64(), process.mem()),
+        Err(_) => (0.0, 0),
+    };
+    info!(
+        "Bench: {:.1} tasks/s, {:.3}s average wait to start, {:.3}s over the ideal {:.3}s",
+        throughput,
+        avg_queued,
+        wall_time - ideal_time,
+        ideal_time
+    );
+    info!(
+        "Bench: cirno used {:.3}s cpu ({:.1}% of wall time), {} resident",
+        cpu_time,
+        cpu_time / wall_time.max(f64::EPSILON) * 100.0,
+        format_mem_size(mem)
+    );
+}
+
+// print the answers, exit with 1 if any command failed
+fn ctl(ctl_args: CtlArgs) {
+    let answers = match send_commands(Path::new(&ctl_args.socket_file), &ctl_args.commands) {
+        Ok(answers) => answers,
+        Err(e) => {
+            eprintln!("Failed to talk to {}: {}", ctl_args.socket_file, e);
+            std::process::exit(1);
+        }
+    };
+    for answer in &answers {
+        println!("{}", answer);
+    }
+    if answers.iter().any(|answer| answer.starts_with("error")) {
+        std::process::exit(1);
+    }
+}
+
 fn main() {
-    let cli_args = Args::parse();
-    let input_list = &cli_args.input_list;
-    let with_task_name = cli_args.with_task_name;
+    match std::env::args_os().nth(1) {
+        Some(arg) if arg == "bench" => {
+            bench(BenchArgs::parse_from(std::env::args_os().skip(1)));
+            return;
+        }
+        Some(arg) if arg == "ctl" => {
+            ctl(CtlArgs::parse_from(std::env::args_os().skip(1)));
+            return;
+        }
+        _ => {}
+    }
+    let cli_args = Args::parse_with_config(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    if let Err(e) = cli_args.validate() {
+        Args::command().error(ErrorKind::ValueValidation, e).exit();
+    }
+    if cli_args.print_config {
+        print_config(&cli_args);
+        return;
+    }
+    // for messages, `a.list, b.list`
+    let input_list = &cli_args.input_list.join(", ");
+    let naming = if cli_args.name_first_token {
+        TaskNaming::FirstToken
+    } else if cli_args.with_task_name {
+        TaskNaming::Comma
+    } else {
+        TaskNaming::Generated
+    };
 
     let mut scheduler = Scheduler::new(&cli_args);
-    for task in gen_tasks_from_file(Path::new(input_list), with_task_name) {
+    // only one list can be watched, see `Args::validate`
+    let mut input_watcher = cli_args.watch_input.then(|| {
+        InputWatcher::new(
+            Path::new(&cli_args.input_list[0]),
+            naming,
+            &cli_args.only,
+            &cli_args.exclude,
+        )
+    });
+    let mut tasks = Vec::new();
+    // where each name was first seen, the same name in two lists is likely a mistake
+    let mut name_sources: HashMap<String, &str> = HashMap::new();
+    for input_file in &cli_args.input_list {
+        let input_path = Path::new(input_file);
+        let file_tasks = match (&mut input_watcher, cli_args.input_format) {
+            (Some(input_watcher), _) => input_watcher.poll(),
+            (None, InputFormat::Line) => gen_tasks_from_file(input_path, naming),
+            (None, InputFormat::Null) => gen_tasks_from_null_file(input_path, naming),
+        };
+        let file_tasks = match file_tasks {
+            Ok(file_tasks) => file_tasks,
+            Err(e) => {
+                let resolved = std::env::current_dir()
+                    .map(|cwd| cwd.join(input_path))
+                    .unwrap_or_else(|_| input_path.to_path_buf());
+                error!(
+                    "Failed to read task list `{}`: {} (looked for {})",
+                    input_file,
+                    e,
+                    resolved.display()
+                );
+                std::process::exit(1);
+            }
+        };
+        if naming.is_named() {
+            for task in &file_tasks {
+                let source = *name_sources
+                    .entry(task.get_name().to_string())
+                    .or_insert(input_file);
+                if source != input_file {
+                    warn!(
+                        "Task name {} in `{}` is also used in `{}`",
+                        task.get_name(),
+                        input_file,
+                        source
+                    );
+                }
+            }
+        }
+        tasks.extend(file_tasks);
+    }
+    if let Some(task_meta) = &cli_args.task_meta {
+        if let Err(e) = apply_task_meta(&mut tasks, Path::new(task_meta)) {
+            error!("Failed to apply task meta `{}`: {}", task_meta, e);
+            std::process::exit(1);
+        }
+    }
+    filter_tasks(&mut tasks, &cli_args.only, &cli_args.exclude);
+    if cli_args.dedup {
+        if let Err(e) = dedup_tasks(&mut tasks, naming.is_named()) {
+            error!("Failed to dedup task list `{}`: {}", input_list, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(seed) = cli_args.shuffle {
+        let seed = seed.unwrap_or_else(|| unix_time().as_nanos() as u64);
+        info!("Shuffle tasks with seed {}", seed);
+        shuffle_tasks(&mut tasks, seed);
+    }
+    if tasks.is_empty() {
+        if cli_args.fail_on_empty {
+            error!("No tasks to run in `{}`", input_list);
+            std::process::exit(1);
+        }
+        warn!("No tasks to run in `{}`", input_list);
+    }
+    for task in tasks {
         scheduler.submit(task);
     }
+    if cli_args.resume {
+        scheduler.resume(naming.is_named());
+    }
+    if let Some(input_watcher) = input_watcher {
+        scheduler.set_input_watcher(input_watcher);
+    }
     let _ = signal_hook::flag::register(signal_hook::consts::SIGINT, scheduler.get_stop_flag_ref());
     let _ =
-        signal_hook::flag::register(signal_hook::consts::SIGTERM, scheduler.get_stop_flag_ref());
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, scheduler.get_term_flag_ref());
+    let _ =
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, scheduler.get_dump_flag_ref());
 
-    scheduler.start();
+    let result = scheduler.start();
     scheduler.write_report();
+    log_summary(&result);
+
+    // drop scheduler first, so remaining task trees are killed before exit
+    let exit_code = scheduler.exit_code();
+    drop(scheduler);
+    std::process::exit(exit_code);
 }
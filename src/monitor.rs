@@ -1,17 +1,53 @@
 use crate::utils::cli::Args;
 use crate::utils::gpu::{GpuInfo, GpuVendor};
-use crate::utils::process::get_process_tree;
+use crate::utils::process::get_process_tree_with_depth;
+use crate::utils::unit::format_mem_size;
+use log::{debug, info, warn};
+use rustix::fs::statvfs;
 use rustix::process::getpid;
 
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::time::Instant;
 use sysinfo::System;
 
-#[derive(PartialEq, Eq, Debug)]
+// measured memory per task over --per-task-mem by this factor, for this many
+// readings in a row, means the estimate is too low
+const OVERCOMMIT_FACTOR: f64 = 1.5;
+const OVERCOMMIT_READINGS: usize = 5;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum SysStatus {
     Health,
     Normal,
     Bad,
 }
 
+impl Display for SysStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let display_str = match self {
+            Self::Health => "Health",
+            Self::Normal => "Normal",
+            Self::Bad => "Bad",
+        };
+        write!(f, "{}", display_str)
+    }
+}
+
+// values seen by the last `Monitor::is_ok`, `Byte` as memory unit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MonitorReadings {
+    pub load_avg: f64,
+    pub swap_usage: f64,
+    pub mem_used: usize,
+    pub per_task_mem: usize,
+    // measured from the process tree of cirno, to compare with --per-task-mem
+    pub os_per_task_mem: usize,
+    // busiest disk since the previous reading, 1 for always busy
+    pub disk_util: f64,
+}
+
 pub struct Monitor {
     system: System,
 
@@ -19,19 +55,46 @@ pub struct Monitor {
     low_mem_thres: usize,
 
     per_task_mem: usize,
-    reversed_mem: usize,
+    // set by the user, not derived from the total memory
+    per_task_mem_given: bool,
+    // readings in a row over the estimate, and the largest one seen
+    overcommit_readings: usize,
+    overcommit_peak: usize,
+    overcommit_warned: bool,
 
     load_avg_thres: f64,
+    swap_thres: f64,
 
     with_gpu: bool,
+    gpu_vendor: GpuVendor,
     gpu_mem_thres: f64,
+    gpu_util_thres: f64,
+    // warn once when gpu queries start failing, not every tick
+    gpu_query_failed: bool,
+    gpu_zero_total_warned: bool,
+
+    watch_dirs: Vec<String>,
+    watch_dir_thres: f64,
+
+    disk_util_thres: f64,
+    // of task trees, cirno itself is one level above
+    tree_depth: Option<usize>,
+    // time spent doing io of each disk, by the previous reading
+    last_diskstats: Option<(Instant, HashMap<String, u64>)>,
+
+    readings: MonitorReadings,
 }
 
 impl Monitor {
     pub fn new(args: &Args) -> Self {
         let system = System::new_all();
         let total_res_mem = system.total_memory() as usize;
-        let per_task_mem = args.per_task_mem;
+        // auto, share the machine between max workers
+        let per_task_mem = if args.per_task_mem == 0 {
+            total_res_mem / args.workers.max(1)
+        } else {
+            args.per_task_mem
+        };
         let reversed_mem = args.reversed_mem;
         let high_mem_thres = (args.high_mem_thres * total_res_mem as f64) as usize;
         let low_mem_thres = (args.low_mem_thres * total_res_mem as f64) as usize;
@@ -55,45 +118,126 @@ impl Monitor {
             high_mem_thres,
             low_mem_thres,
             per_task_mem,
-            reversed_mem,
+            per_task_mem_given: args.per_task_mem != 0,
+            overcommit_readings: 0,
+            overcommit_peak: 0,
+            overcommit_warned: false,
             load_avg_thres: args.load_avg_thres,
+            swap_thres: args.swap_thres,
             with_gpu,
+            gpu_vendor: args.gpu_vendor,
             gpu_mem_thres,
+            gpu_util_thres: args.gpu_util_thres,
+            gpu_query_failed: false,
+            gpu_zero_total_warned: false,
+            watch_dirs: args.watch_dir.clone(),
+            watch_dir_thres: args.watch_dir_thres,
+            disk_util_thres: args.disk_util_thres,
+            tree_depth: args.tree_depth,
+            last_diskstats: None,
+            readings: MonitorReadings::default(),
         }
     }
 
-    pub fn is_ok(&mut self, running_task_amount: usize) -> SysStatus {
+    pub fn get_high_mem_thres(&self) -> usize {
+        self.high_mem_thres
+    }
+
+    pub fn get_low_mem_thres(&self) -> usize {
+        self.low_mem_thres
+    }
+
+    pub fn get_per_task_mem(&self) -> usize {
+        self.per_task_mem
+    }
+
+    pub fn set_per_task_mem(&mut self, per_task_mem: usize) {
+        self.per_task_mem = per_task_mem;
+        self.per_task_mem_given = true;
+    }
+
+    pub fn get_gpu_mem_thres(&self) -> f64 {
+        self.gpu_mem_thres
+    }
+
+    pub fn get_readings(&self) -> MonitorReadings {
+        self.readings
+    }
+
+    // if memory in use by the last readings is above the low threshold
+    pub fn is_mem_high(&self) -> bool {
+        self.readings.mem_used > self.low_mem_thres
+    }
+
+    // how close the last readings are to the thresholds, 0 for idle and 1 at the limit
+    pub fn pressure(&self) -> f64 {
+        let mem_pressure = self.readings.mem_used as f64 / self.high_mem_thres.max(1) as f64;
+        let load_pressure = self.readings.load_avg / self.load_avg_thres;
+        mem_pressure.max(load_pressure).clamp(0.0, 1.0)
+    }
+
+    // take the readings of this tick, every check and `pressure` go by them
+    pub fn refresh(&mut self, running_task_amount: usize) {
         // update monitor
         self.system.refresh_memory();
 
         // check system load average
-        let load_avg = System::load_average().five / self.system.cpus().len() as f64;
-        if load_avg > self.load_avg_thres * 2.0 {
-            return SysStatus::Bad;
+        self.readings.load_avg = System::load_average().five / self.system.cpus().len() as f64;
+
+        // heavy swapping means memory is overcommitted
+        let total_swap = self.system.total_swap();
+        if self.swap_thres >= 0.0 {
+            // no swap at all, or a swapoff while running, is no swapping
+            self.readings.swap_usage = match total_swap {
+                0 => 0.0,
+                _ => self.system.used_swap() as f64 / total_swap as f64,
+            };
         }
 
         // try to statistc per task mem usage
-        let process_list = get_process_tree(getpid(), false).unwrap();
+        // a failed walk counts as no task memory, the system readings still apply
+        let tree_depth = self.tree_depth.map(|depth| depth + 1);
+        let process_list = match get_process_tree_with_depth(getpid(), false, tree_depth) {
+            Ok(process_list) => process_list,
+            Err(e) => {
+                warn!("Failed to read process tree of cirno: {}", e);
+                Vec::new()
+            }
+        };
         let mut total_mem = 0;
         for process in process_list {
             total_mem += process.mem();
         }
 
         // `Byte` unit
-        let os_per_task_mem = if running_task_amount == 0 {
-            0
-        } else {
-            total_mem / running_task_amount
-        };
-        let per_task_mem = if self.per_task_mem >= os_per_task_mem {
+        let os_per_task_mem = total_mem.checked_div(running_task_amount).unwrap_or(0);
+        self.readings.os_per_task_mem = os_per_task_mem;
+        self.check_overcommit(os_per_task_mem);
+        self.readings.per_task_mem = if self.per_task_mem >= os_per_task_mem {
             self.per_task_mem
         } else {
             os_per_task_mem
         };
+        self.readings.mem_used = self.system.used_memory() as usize;
+
+        if self.disk_util_thres >= 0.0 {
+            if let Some(disk_util) = self.sample_disk_util() {
+                self.readings.disk_util = disk_util;
+            }
+        }
+    }
+
+    // judge the readings of the last `refresh`
+    pub fn is_ok(&mut self) -> SysStatus {
+        if self.readings.load_avg > self.load_avg_thres * 2.0 {
+            return SysStatus::Bad;
+        }
+        if self.swap_thres >= 0.0 && self.readings.swap_usage > self.swap_thres {
+            return SysStatus::Bad;
+        }
 
-        let os_total_mem_used = self.system.used_memory() as usize;
         // if mem has free
-        let predicate_mem_used = os_total_mem_used + per_task_mem;
+        let predicate_mem_used = self.readings.mem_used + self.readings.per_task_mem;
         let mut sys_status_res = if predicate_mem_used <= self.low_mem_thres {
             SysStatus::Health
         } else if predicate_mem_used > self.high_mem_thres {
@@ -103,21 +247,188 @@ impl Monitor {
         };
 
         // check gpu usage
-        if self.with_gpu && sys_status_res == SysStatus::Health {
-            let gpu_cards = GpuInfo::get_gpus_info(GpuVendor::Nvidia);
-            let mut has_free_card = false;
-            for card in gpu_cards {
-                if card.memory_free / card.memory_total >= self.gpu_mem_thres {
-                    has_free_card = true;
-                    break;
+        if self.with_gpu && sys_status_res == SysStatus::Health && self.free_gpus().is_empty() {
+            sys_status_res = SysStatus::Normal;
+        }
+
+        // io bound batches saturate the disk long before memory or cpu
+        if self.disk_util_thres >= 0.0
+            && sys_status_res == SysStatus::Health
+            && self.readings.disk_util > self.disk_util_thres
+        {
+            debug!(
+                "Disk utilization {:.2} is high, hold new tasks",
+                self.readings.disk_util
+            );
+            sys_status_res = SysStatus::Normal;
+        }
+
+        // check scratch space, running out of inodes fails tasks as well as bytes
+        if sys_status_res == SysStatus::Health
+            && !self.watch_dirs.iter().all(|dir| self.is_dir_ok(dir))
+        {
+            sys_status_res = SysStatus::Normal;
+        }
+
+        sys_status_res
+    }
+
+    // indices of cards with enough free memory, a failed query counts as none,
+    // the next call asks again
+    pub fn free_gpus(&mut self) -> Vec<u32> {
+        let gpu_cards = match GpuInfo::get_gpus_info(self.gpu_vendor) {
+            Ok(cards) => {
+                if self.gpu_query_failed {
+                    info!("Gpu query works again");
+                    self.gpu_query_failed = false;
                 }
+                cards
             }
+            Err(e) => {
+                if !self.gpu_query_failed {
+                    warn!("Failed to query gpus, hold new tasks until it works: {}", e);
+                    self.gpu_query_failed = true;
+                } else {
+                    debug!("Failed to query gpus: {}", e);
+                }
+                return Vec::new();
+            }
+        };
+        let mut free_gpus = Vec::new();
+        for card in gpu_cards {
+            let free_ratio = match card.free_ratio() {
+                Some(free_ratio) => free_ratio,
+                None => {
+                    if !self.gpu_zero_total_warned {
+                        warn!(
+                            "Gpu {} ({}) reports {} MiB total memory, skip it",
+                            card.index, card.name, card.memory_total
+                        );
+                        self.gpu_zero_total_warned = true;
+                    }
+                    continue;
+                }
+            };
+            if free_ratio < self.gpu_mem_thres {
+                continue;
+            }
+            // compute bound tasks fill a card long before its memory,
+            // cards not reporting utilization are judged by memory alone
+            if self.gpu_util_thres >= 0.0
+                && card
+                    .utilization
+                    .is_some_and(|utilization| utilization > self.gpu_util_thres)
+            {
+                debug!("Gpu {} is busy, not free", card.index);
+                continue;
+            }
+            free_gpus.push(card.index);
+        }
+        free_gpus
+    }
+
+    pub fn get_gpu_vendor(&self) -> GpuVendor {
+        self.gpu_vendor
+    }
 
-            if !has_free_card {
-                sys_status_res = SysStatus::Normal;
+    // warn once per batch when tasks keep using more than --per-task-mem
+    fn check_overcommit(&mut self, os_per_task_mem: usize) {
+        if !self.per_task_mem_given || self.overcommit_warned {
+            return;
+        }
+        if (os_per_task_mem as f64) <= self.per_task_mem as f64 * OVERCOMMIT_FACTOR {
+            self.overcommit_readings = 0;
+            self.overcommit_peak = 0;
+            return;
+        }
+        self.overcommit_readings += 1;
+        self.overcommit_peak = self.overcommit_peak.max(os_per_task_mem);
+        if self.overcommit_readings >= OVERCOMMIT_READINGS {
+            warn!(
+                "Tasks use {} each, more than --per-task-mem {}, consider --per-task-mem {}",
+                format_mem_size(os_per_task_mem),
+                format_mem_size(self.per_task_mem),
+                format_mem_size(self.overcommit_peak)
+            );
+            self.overcommit_warned = true;
+        }
+    }
+
+    // like `%util` of iostat, none for the first reading
+    fn sample_disk_util(&mut self) -> Option<f64> {
+        let now = Instant::now();
+        let io_ticks = match read_disk_io_ticks() {
+            Ok(io_ticks) => io_ticks,
+            Err(e) => {
+                debug!("Failed to read diskstats: {}", e);
+                return None;
+            }
+        };
+        let disk_util = match &self.last_diskstats {
+            Some((last_time, last_io_ticks)) => {
+                let elapsed = now.duration_since(*last_time).as_millis().max(1) as f64;
+                io_ticks
+                    .iter()
+                    .filter_map(|(disk, ticks)| {
+                        let last_ticks = last_io_ticks.get(disk)?;
+                        Some(ticks.saturating_sub(*last_ticks) as f64 / elapsed)
+                    })
+                    .reduce(f64::max)
+                    .map(|disk_util| disk_util.min(1.0))
+            }
+            None => None,
+        };
+        self.last_diskstats = Some((now, io_ticks));
+        disk_util
+    }
+
+    // an unreadable dir is not held against the tasks, only warned
+    fn is_dir_ok(&self, dir: &str) -> bool {
+        let stat = match statvfs(dir) {
+            Ok(stat) => stat,
+            Err(e) => {
+                warn!("Failed to statvfs watched dir {}: {}", dir, e);
+                return true;
+            }
+        };
+        // some filesystems, like btrfs, report no inode limit at all
+        let ratios = [
+            (stat.f_bavail, stat.f_blocks, "space"),
+            (stat.f_favail, stat.f_files, "inodes"),
+        ];
+        for (free, total, what) in ratios {
+            if total > 0 && (free as f64 / total as f64) < self.watch_dir_thres {
+                debug!("Low free {} in {}: {} of {}", what, dir, free, total);
+                return false;
             }
         }
+        true
+    }
+}
 
-        sys_status_res
+// milliseconds spent doing io of each whole disk, memory backed ones are skipped
+fn read_disk_io_ticks() -> std::io::Result<HashMap<String, u64>> {
+    let diskstats = std::fs::read_to_string("/proc/diskstats")?;
+    let mut io_ticks = HashMap::new();
+    for line in diskstats.lines() {
+        let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+        let (name, ticks) = match (fields.get(2), fields.get(12)) {
+            (Some(name), Some(ticks)) => (*name, *ticks),
+            _ => continue,
+        };
+        if ["loop", "ram", "zram"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+        // partitions have no entry of their own in /sys/block
+        if !Path::new("/sys/block").join(name).exists() {
+            continue;
+        }
+        if let Ok(ticks) = ticks.parse::<u64>() {
+            io_ticks.insert(name.to_string(), ticks);
+        }
     }
+    Ok(io_ticks)
 }
@@ -1,84 +1,534 @@
-use std::collections::VecDeque;
-use std::io::Write;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufWriter, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::control::ControlSocket;
 use crate::monitor::{Monitor, SysStatus};
-use crate::task::{Task, TaskStatus};
-use crate::utils::cli::Args;
+use crate::task::{InputWatcher, Task, TaskStatus, STDIN_INPUT};
+use crate::utils::cli::{
+    Args, ExitCodeMode, KillScope, OutputMode, ReportFormat, ReportOrder, VERSION,
+};
+use crate::utils::clock::{unix_time, Clock, SystemClock};
+use crate::utils::gpu::GpuVendor;
+use crate::utils::process::{allowed_cpus, controlling_tty, signal_name};
+use crate::utils::unit::parse_mem_size;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use indicatif_log_bridge::LogWrapper;
-use log::{debug, warn};
+use log::{debug, info, warn};
+use rustix::process::{getpriority_process, setpriority_process, Signal};
+use serde_json::json;
+
+// tasks with this label share the --heavy-workers cap
+const HEAVY_LABEL: &str = "heavy";
+// tasks with this label only run alone, the batch drains before one starts
+const EXCLUSIVE_LABEL: &str = "exclusive";
+// a task blocked reading the terminal this long is waiting for a person, seconds
+const TTY_WAIT: f64 = 2.0;
 
 pub struct Scheduler {
     // spaces for tasks
     waiting_queue: VecDeque<Task>,
     running_pool: Vec<Task>,
-    timeout_pool: Vec<Task>,
-    force_stop_pool: Vec<Task>,
+    // with the steps of --timeout-signal sent so far
+    timeout_pool: Vec<(Task, usize)>,
+    // with the ticks left to wait before SIGKILL
+    force_stop_pool: Vec<(Task, usize)>,
+    // tasks signaled to leave memory, requeued once they exit
+    mem_stop_pool: Vec<Task>,
     exited_pool: Vec<Task>,
+    // tasks of exited_pool already in the report, none before it is created
+    report_written: Option<usize>,
+    report_order: ReportOrder,
+    report_format: ReportFormat,
+    // report entries of tasks skipped by `resume`
+    resumed_report: Vec<String>,
+    // tasks submitted so far, the next submit index
+    submitted: usize,
 
     // propreties of scheduler
-    // how many ticks per second
-    tick_rate: f64,
     tick_time: u128,   // miliseconds
     timeout: f64,      // seconds
     timeout_wait: f64, // seconds
+    idle_timeout: f64, // seconds
+
+    mem_kill_signal: Signal,
+    // signals for timed out tasks with the seconds to wait after each,
+    // empty for ALRM every tick, then INT and KILL
+    timeout_signals: Vec<(Signal, f64)>,
+    mem_kill_grace: f64,      // seconds
+    min_run_before_kill: f64, // seconds
+    min_healthy_runtime: f64, // seconds
+    kill_scope: KillScope,
+    tree_depth: Option<usize>,
+    // terminal cirno runs on, tasks reading it stall the batch
+    tty: Option<(u32, u32)>,
 
     max_workers: usize,
+    min_workers: Option<usize>,
     force_workers: usize,
+    // cap for tasks labeled `heavy`, under the worker cap
+    heavy_workers: Option<usize>,
+    // an exclusive task is next, only exclusive tasks may start until it does
+    exclusive_hold: bool,
+    // worker cap while ramping up at batch start, None once done or disabled
+    ramp_cap: Option<usize>,
+    priority_aging: f64,
+    // run only this many tasks, count of tasks started so far
+    limit: Option<usize>,
+    dispatched: usize,
 
     monitor: Monitor,
+    last_status: Option<SysStatus>,
+    on_status_change: Option<String>,
+    clock: Arc<dyn Clock>,
     stop_flag: Arc<AtomicBool>,
+    dump_flag: Arc<AtomicBool>,
+    // set by SIGTERM, the first one drains the batch, a second one stops it
+    term_flag: Arc<AtomicBool>,
+    // since when no new task starts and running ones are waited for
+    draining_since: Option<Instant>,
+    drain_timeout: f64, // seconds
+    multi_pbar: MultiProgress,
 
     run_dir: String,
+    log_shard: usize,
+    output_mode: OutputMode,
+    heartbeat_file: Option<PathBuf>,
+    control: Option<ControlSocket>,
+    // set over the control socket, nothing new is spawned while running tasks are still watched
+    paused: bool,
+    // with either the batch runs until stopped
+    daemon: bool,
+    input_watcher: Option<InputWatcher>,
+    // nice value for tasks when cirno reniced itself
+    task_nice: Option<i32>,
+    nice: Option<i32>,
+    ionice: Option<i32>,
+    // RLIMIT_AS of every task
+    task_mem_limit: Option<usize>,
+
+    allow_commands: Vec<String>,
+    deny_commands: Vec<String>,
+
+    exit_code_mode: ExitCodeMode,
+    stream_results: bool,
+    phase_timing: bool,
+    max_spawns_per_tick: usize,
+    force_stop_retries: usize,
+    max_retries: usize,
+    // with --gpus-per-task, cards held by alive tasks are not handed out again
+    gpus_per_task: usize,
+    // with --cpu-affinity the cpus cirno may use, split into a range per worker slot,
+    // laid out once for --workers so a changed worker cap never makes ranges overlap
+    affinity_slots: Option<Vec<Vec<usize>>>,
+    // with --profile, and the last cpu time sampled for each task
+    profile_file: Option<std::fs::File>,
+    profile_samples: HashMap<String, (Instant, Duration)>,
 }
 
 impl Scheduler {
     pub fn new(args: &Args) -> Self {
         let tick_time = (1000.0 / args.tick_rate) as u128;
         let monitor = Monitor::new(args);
-        let res = Scheduler {
+
+        // init logger early, so messages during submit are shown
+        let multi_pbar = MultiProgress::new();
+        let mut builder =
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+        let log_file = args.log_file.as_ref().and_then(|log_file| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)
+                .inspect_err(|e| eprintln!("Failed to open log file {}: {}", log_file, e))
+                .ok()
+        });
+        // only the first scheduler of the process sets the logger
+        let _ = match log_file {
+            // the bars stay alone on the terminal
+            Some(log_file) => builder
+                .target(env_logger::Target::Pipe(Box::new(log_file)))
+                .write_style(env_logger::WriteStyle::Never)
+                .try_init(),
+            None => LogWrapper::new(multi_pbar.clone(), builder.build()).try_init(),
+        };
+
+        let mut res = Scheduler {
             waiting_queue: VecDeque::new(),
             running_pool: Vec::new(),
             timeout_pool: Vec::new(),
             force_stop_pool: Vec::new(),
+            mem_stop_pool: Vec::new(),
             exited_pool: Vec::new(),
+            report_written: None,
+            report_order: args.report_order,
+            report_format: args.report_format,
+            resumed_report: Vec::new(),
+            submitted: 0,
 
-            tick_rate: args.tick_rate,
             tick_time,
             timeout: args.timeout,
             timeout_wait: args.timeout_wait,
+            idle_timeout: args.idle_timeout,
+
+            mem_kill_signal: args.mem_kill_signal,
+            timeout_signals: args
+                .timeout_signal
+                .iter()
+                .map(|(signal, delay)| (*signal, delay.unwrap_or(args.timeout_wait)))
+                .collect(),
+            mem_kill_grace: args.mem_kill_grace,
+            min_run_before_kill: args.min_run_before_kill,
+            min_healthy_runtime: args.min_healthy_runtime,
+            kill_scope: args.kill_scope,
+            tree_depth: args.tree_depth,
+            tty: controlling_tty(),
 
             max_workers: args.workers,
+            min_workers: args.min_workers,
             force_workers: args.force_workers,
+            heavy_workers: args.heavy_workers,
+            exclusive_hold: false,
+            ramp_cap: args.ramp_up.then_some(args.force_workers),
+            priority_aging: args.priority_aging,
+            limit: args.limit,
+            dispatched: 0,
 
             monitor,
+            last_status: None,
+            on_status_change: args.on_status_change.clone(),
+            clock: Arc::new(SystemClock),
             stop_flag: Arc::new(AtomicBool::new(false)),
+            dump_flag: Arc::new(AtomicBool::new(false)),
+            term_flag: Arc::new(AtomicBool::new(false)),
+            draining_since: None,
+            drain_timeout: args.drain_timeout,
+            multi_pbar,
 
             run_dir: args.run_dir.clone(),
+            log_shard: args.log_shard,
+            output_mode: args.output_mode,
+            heartbeat_file: args.heartbeat_file.as_ref().map(PathBuf::from),
+            control: None,
+            paused: false,
+            daemon: args.daemon,
+            input_watcher: None,
+            task_nice: None,
+            nice: args.nice,
+            ionice: args.ionice,
+            task_mem_limit: args.task_mem_limit,
+
+            allow_commands: args.allow_commands.clone(),
+            deny_commands: args.deny_commands.clone(),
+
+            exit_code_mode: args.exit_code_mode,
+            stream_results: args.stream_results,
+            phase_timing: args.phase_timing,
+            max_spawns_per_tick: args.max_spawns_per_tick,
+            force_stop_retries: args.force_stop_retries,
+            max_retries: args.max_retries,
+            gpus_per_task: args.gpus_per_task,
+            affinity_slots: args
+                .cpu_affinity
+                .then(|| split_cpu_slots(&allowed_cpus(), args.workers)),
+            profile_file: None,
+            profile_samples: HashMap::new(),
         };
         res.init_runtime();
+        if args.profile {
+            res.open_profile();
+        }
+        if let Some(socket_file) = &args.socket_file {
+            res.open_control(Path::new(socket_file));
+        }
+        res.write_run_info(args);
+        res.set_self_nice(args.self_nice);
         res
     }
 
+    fn open_control(&mut self, socket_file: &Path) {
+        match ControlSocket::bind(socket_file) {
+            Ok(control) => {
+                info!("Listen on control socket {}", socket_file.display());
+                self.control = Some(control);
+            }
+            Err(e) => warn!(
+                "Failed to bind control socket {}: {}",
+                socket_file.display(),
+                e
+            ),
+        }
+    }
+
+    // serve the control socket, taken out so the handler can borrow the scheduler
+    fn poll_control(&mut self) {
+        if let Some(mut control) = self.control.take() {
+            control.poll(|line| self.handle_control(line));
+            self.control = Some(control);
+        }
+    }
+
+    // a `key=value` line from the control socket, the answer starts with `ok` or `error`,
+    // except for `status` answered with a json line
+    fn handle_control(&mut self, line: &str) -> String {
+        match line {
+            "status" => return self.status_snapshot().to_string(),
+            "pause" => {
+                if !self.paused {
+                    info!("Paused, no new task is started until resumed");
+                }
+                self.paused = true;
+                return "ok paused".to_string();
+            }
+            "resume" => {
+                if self.paused {
+                    info!("Resumed");
+                }
+                self.paused = false;
+                return "ok resumed".to_string();
+            }
+            _ => {}
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return format!("error: expected key=value, got `{}`", line),
+        };
+        match key {
+            "workers" => match value.parse::<usize>() {
+                Ok(workers) if workers >= 1 && workers >= self.force_workers => {
+                    self.max_workers = workers;
+                    format!("ok workers={}", workers)
+                }
+                Ok(workers) => format!(
+                    "error: workers ({}) must be at least 1 and force_workers ({})",
+                    workers, self.force_workers
+                ),
+                Err(_) => format!("error: bad workers `{}`", value),
+            },
+            "force_workers" => match value.parse::<usize>() {
+                Ok(force_workers) if force_workers <= self.max_workers => {
+                    self.force_workers = force_workers;
+                    format!("ok force_workers={}", force_workers)
+                }
+                Ok(force_workers) => format!(
+                    "error: force_workers ({}) is larger than workers ({})",
+                    force_workers, self.max_workers
+                ),
+                Err(_) => format!("error: bad force_workers `{}`", value),
+            },
+            "per-task-mem" | "per_task_mem" => match parse_mem_size(value) {
+                Some(per_task_mem) if per_task_mem > 0 => {
+                    self.monitor.set_per_task_mem(per_task_mem);
+                    format!("ok per-task-mem={}", per_task_mem)
+                }
+                _ => format!("error: bad per-task-mem `{}`", value),
+            },
+            _ => format!("error: unknown key `{}`", key),
+        }
+    }
+
+    // renice cirno, tasks are put back to the nice value cirno started with
+    fn set_self_nice(&mut self, self_nice: Option<i32>) {
+        let self_nice = match self_nice {
+            Some(self_nice) => self_nice,
+            None => return,
+        };
+        let task_nice = getpriority_process(None).unwrap_or(0);
+        match setpriority_process(None, self_nice) {
+            Ok(_) => {
+                info!("Set nice value of cirno to {}", self_nice);
+                self.task_nice = Some(task_nice);
+            }
+            Err(e) => warn!("Failed to set nice value of cirno to {}: {}", self_nice, e),
+        }
+    }
+
     fn init_runtime(&self) {
         std::fs::create_dir_all(&self.run_dir).expect("Failed to create runtime directory");
     }
 
+    fn open_profile(&mut self) {
+        let profile_path = format!("{}/cirno_profile.csv", self.run_dir);
+        match std::fs::File::create(&profile_path) {
+            Ok(mut file) => {
+                let _ = writeln!(file, "task,timestamp,rss,cpu_pct");
+                self.profile_file = Some(file);
+            }
+            Err(e) => warn!("Failed to create {}: {}", profile_path, e),
+        }
+    }
+
+    // one row per task still holding its process, cpu is the share of
+    // one core used since the previous sample
+    fn sample_profile(&mut self) {
+        let file = match &mut self.profile_file {
+            Some(file) => file,
+            None => return,
+        };
+        let timestamp = unix_time().as_secs_f64();
+        let now = Instant::now();
+        let mut samples = HashMap::new();
+        let mut rows = String::new();
+        let tasks = self
+            .running_pool
+            .iter()
+            .chain(self.timeout_pool.iter().map(|(task, _)| task))
+            .chain(self.force_stop_pool.iter().map(|(task, _)| task))
+            .chain(self.mem_stop_pool.iter());
+        for task in tasks {
+            let cpu_time = task.cpu_time();
+            let cpu_pct = match self.profile_samples.get(task.get_name()) {
+                Some((last, last_cpu_time)) if now > *last => {
+                    cpu_time.saturating_sub(*last_cpu_time).as_secs_f64()
+                        / now.duration_since(*last).as_secs_f64()
+                        * 100.0
+                }
+                _ => 0.0,
+            };
+            rows.push_str(&format!(
+                "{},{:.3},{},{:.1}\n",
+                task.get_name(),
+                timestamp,
+                task.mem(),
+                cpu_pct
+            ));
+            samples.insert(task.get_name().to_string(), (now, cpu_time));
+        }
+        self.profile_samples = samples;
+        if let Err(e) = file.write_all(rows.as_bytes()) {
+            warn!("Failed to write profile: {}", e);
+        }
+    }
+
+    // record how this batch was launched, next to the task report
+    fn write_run_info(&self, args: &Args) {
+        let config = args
+            .to_toml()
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|table| serde_json::to_value(table).ok());
+        let start_time = unix_time().as_secs_f64();
+        // stdin is read once, by main
+        let input: Vec<_> = args
+            .input_list
+            .iter()
+            .map(|input_file| {
+                let input_hash = (input_file != STDIN_INPUT)
+                    .then(|| std::fs::read(input_file).ok())
+                    .flatten()
+                    .map(|contents| format!("{:016x}", fnv1a(&contents)));
+                json!({
+                    "path": input_file,
+                    "fnv1a": input_hash,
+                })
+            })
+            .collect();
+
+        let run_info = json!({
+            "command_line": std::env::args().collect::<Vec<_>>(),
+            "config": config,
+            "hostname": sysinfo::System::host_name(),
+            "start_time": start_time,
+            "version": VERSION,
+            "input": input,
+        });
+
+        let run_info_path = format!("{}/cirno_run.json", self.run_dir);
+        match serde_json::to_string_pretty(&run_info) {
+            Ok(run_info) => {
+                if let Err(e) = std::fs::write(&run_info_path, run_info) {
+                    warn!("Failed to write run info: {}", e);
+                }
+            }
+            Err(e) => warn!("Failed to write run info: {}", e),
+        }
+    }
+
     pub fn get_stop_flag_ref(&self) -> Arc<AtomicBool> {
         Arc::clone(&self.stop_flag)
     }
 
-    pub fn submit(&mut self, task: Task) {
+    // set it to drain the batch on the next tick, or stop it if already draining
+    pub fn get_term_flag_ref(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.term_flag)
+    }
+
+    // set it to dump the scheduler state into run_dir on the next tick
+    pub fn get_dump_flag_ref(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.dump_flag)
+    }
+
+    // the clock given to every task, tasks already submitted are switched too
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        for task in self.waiting_queue.iter_mut() {
+            task.set_clock(Arc::clone(&clock));
+        }
+        self.clock = clock;
+    }
+
+    pub fn set_input_watcher(&mut self, input_watcher: InputWatcher) {
+        self.input_watcher = Some(input_watcher);
+    }
+
+    pub fn submit(&mut self, mut task: Task) {
+        task.set_clock(Arc::clone(&self.clock));
+        task.mark_submitted(self.submitted);
+        self.submitted += 1;
+        task.set_kill_scope(self.kill_scope);
+        task.set_tree_depth(self.tree_depth);
+        task.set_max_retries(self.max_retries);
+        // the task option, then --nice, then back to the nice value before --self-nice
+        if let Some(nice) = task.get_nice().or(self.nice).or(self.task_nice) {
+            task.set_nice(nice);
+        }
+        if let Some(ionice) = self.ionice {
+            task.set_io_priority(ionice);
+        }
+        if let Some(task_mem_limit) = self.task_mem_limit {
+            task.set_mem_limit(task_mem_limit);
+        }
+        let reason = std::iter::once(task.get_prog())
+            .chain(task.get_retry_prog())
+            .find_map(|prog| self.check_command(prog));
+        if let Some(reason) = reason {
+            warn!("Rejected {}: {}", task.get_name(), reason);
+            task.set_status(TaskStatus::Rejected);
+            task.set_reason(&reason);
+            self.exit_task(task);
+            return;
+        }
         self.waiting_queue.push_back(task);
     }
 
-    pub fn start(&mut self) {
+    // match both the program as written and its file name,
+    // so `rm` also covers `/bin/rm`
+    fn check_command(&self, prog: &str) -> Option<String> {
+        let file_name = Path::new(prog)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(prog);
+        let is_match = |name: &String| name == prog || name == file_name;
+
+        if self.deny_commands.iter().any(is_match) {
+            return Some(format!("command `{}` is in --deny-commands", prog));
+        }
+        if !self.allow_commands.is_empty() && !self.allow_commands.iter().any(is_match) {
+            return Some(format!("command `{}` is not in --allow-commands", prog));
+        }
+        None
+    }
+
+    pub fn start(&mut self) -> BatchResult {
+        let batch_start = self.clock.now();
         self.run();
+        self.batch_result(self.clock.elapsed(batch_start))
     }
 
     fn run(&mut self) {
@@ -89,15 +539,7 @@ impl Scheduler {
         .progress_chars("=>-");
         let msg_style = ProgressStyle::with_template("{spinner} {msg}").unwrap();
 
-        let multi_pbar = MultiProgress::new();
-        let logger =
-            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-                .build();
-
-        LogWrapper::new(multi_pbar.clone(), logger)
-            .try_init()
-            .unwrap();
-
+        let multi_pbar = self.multi_pbar.clone();
         let pbar = multi_pbar.add(ProgressBar::new(self.waiting_queue.len() as u64));
         pbar.set_style(style);
         pbar.enable_steady_tick(Duration::from_millis(100));
@@ -106,11 +548,35 @@ impl Scheduler {
         pmsg_bar.set_style(msg_style);
         pmsg_bar.enable_steady_tick(Duration::from_millis(100));
 
+        let mut phase_timer = PhaseTimer::default();
         loop {
             let tick_start = Instant::now();
+            phase_timer.start();
             debug!("New loop start");
-            let tasks =
-                self.waiting_queue.len() + self.running_pool.len() + self.timeout_pool.len();
+            if let Some(input_watcher) = &mut self.input_watcher {
+                match input_watcher.poll() {
+                    Ok(tasks) => {
+                        // rejected ones are not counted, as at start
+                        let queued = self.waiting_queue.len();
+                        for task in tasks {
+                            self.submit(task);
+                        }
+                        pbar.inc_length((self.waiting_queue.len() - queued) as u64);
+                    }
+                    Err(e) => warn!("Failed to read appended tasks: {}", e),
+                }
+            }
+            self.poll_control();
+            let waiting_tasks = self
+                .waiting_queue
+                .iter()
+                .filter(|task| self.is_pending(task))
+                .count();
+            let tasks = waiting_tasks
+                + self.running_pool.len()
+                + self.timeout_pool.len()
+                + self.force_stop_pool.len()
+                + self.mem_stop_pool.len();
 
             pmsg_bar.set_message(format!(
                 "[running: {}|timeout_wait: {}|exited: {}]",
@@ -119,8 +585,31 @@ impl Scheduler {
                 self.exited_pool.len()
             ));
 
+            phase_timer.lap("intake");
+
             debug!("Checking if should stop");
-            if tasks == 0 || self.stop_flag.load(Ordering::Relaxed) {
+            if self.term_flag.swap(false, Ordering::Relaxed) {
+                self.drain();
+            }
+            let draining_done = self.draining_since.is_some_and(|draining_since| {
+                if self.alive_tasks().next().is_none() {
+                    info!("Drained, every running task has finished");
+                    return true;
+                }
+                let draining_time = self.clock.elapsed(draining_since).as_secs_f64();
+                if self.drain_timeout > 0.0 && draining_time >= self.drain_timeout {
+                    warn!(
+                        "Drain timed out after {:.1}s, stop running tasks",
+                        draining_time
+                    );
+                    return true;
+                }
+                false
+            });
+            // a daemon or a watched input keeps running on an empty queue,
+            // the tick sleep below keeps the idle loop from spinning
+            let drained = tasks == 0 && !self.daemon && self.input_watcher.is_none();
+            if drained || draining_done || self.stop_flag.load(Ordering::Relaxed) {
                 // all task is done.
                 debug!("Cirno Loop Exited");
                 break;
@@ -128,28 +617,97 @@ impl Scheduler {
 
             // write report to file if necessary
             self.write_report();
+            self.beat();
+
+            if self.dump_flag.swap(false, Ordering::Relaxed) {
+                self.dump_state();
+            }
+            phase_timer.lap("report");
+
+            self.sample_profile();
+            phase_timer.lap("profile");
 
             // do schedule
             // Firstly, check running pool for finished and timeout task
             debug!("Checking running pool...");
             let mut remain_running_tasks = Vec::new();
-            for mut task in self.running_pool.drain(..) {
+            for mut task in std::mem::take(&mut self.running_pool) {
                 // check if the task is done
                 match task.try_wait() {
-                    Ok(Some(_)) => {
-                        task.set_status(TaskStatus::Exited);
-                        self.exited_pool.push(task);
+                    Ok(Some(status)) => {
+                        self.check_oom_killed(&task, status);
+                        if task.schedule_next_run() {
+                            debug!("Periodic task queued for next run");
+                            self.waiting_queue.push_back(task);
+                            continue;
+                        }
+                        // still counted as one task by the progress bar
+                        if !status.success() && task.take_retry() {
+                            info!("Task {} failed with {}, retry it", task.get_name(), status);
+                            self.waiting_queue.push_back(task);
+                            continue;
+                        }
+                        // failing right after start usually means a bad command line
+                        let running_time = task.running_time().as_secs_f64();
+                        if !status.success() && running_time < self.min_healthy_runtime {
+                            task.set_status(TaskStatus::FailedFast);
+                            task.set_reason(&format!("failed after {:.1}s", running_time));
+                        } else {
+                            task.set_status(TaskStatus::Exited);
+                        }
+                        self.exit_task(task);
                         pbar.inc(1);
                         debug!("Found Exited");
                     }
                     Ok(None) => {
                         // task is still running
-                        // if task is timeout
-                        if self.timeout > 0.0 && task.running_time().as_secs_f64() >= self.timeout {
+                        // if task prompts on the terminal, nobody may be there to answer
+                        if let Some(tty) = self.tty {
+                            if task.tty_wait_time(tty).as_secs_f64() >= TTY_WAIT {
+                                warn!(
+                                    "Task {} requires interactive input, stopped",
+                                    task.get_name()
+                                );
+                                let _ = task.stop();
+                                task.set_status(TaskStatus::FailedFast);
+                                task.set_reason("requires interactive input");
+                                self.exit_task(task);
+                                pbar.inc(1);
+                                continue;
+                            }
+                        }
+                        // if task is over its memory budget
+                        if let Some(max_rss) = task.get_max_rss() {
+                            let rss = task.mem();
+                            if rss > max_rss {
+                                warn!(
+                                    "Task {} uses {} bytes, over max_rss {}, killed",
+                                    task.get_name(),
+                                    rss,
+                                    max_rss
+                                );
+                                let _ = task.stop();
+                                task.set_status(TaskStatus::OOMKilled);
+                                self.exit_task(task);
+                                pbar.inc(1);
+                                continue;
+                            }
+                        }
+                        // if task is timeout, or has been quiet for too long
+                        let idle_timeout = task.get_idle_timeout().unwrap_or(self.idle_timeout);
+                        let idle_time = task.idle_time().as_secs_f64();
+                        let timeout = task.get_timeout().unwrap_or(self.timeout);
+                        if timeout > 0.0 && task.running_time().as_secs_f64() >= timeout {
                             task.set_status(TaskStatus::Timeout);
                             task.reset_waiting_time();
-                            self.timeout_pool.push(task);
+                            self.timeout_pool.push((task, 0));
                             debug!("Found Timeout");
+                        } else if idle_timeout > 0.0 && idle_time >= idle_timeout {
+                            task.set_status(TaskStatus::Timeout);
+                            task.set_reason(&format!("no output for {:.1}s", idle_time));
+                            task.reset_waiting_time();
+                            self.timeout_pool.push((task, 0));
+                            debug!("Found Idle Timeout");
                         } else {
                             remain_running_tasks.push(task);
                         }
@@ -163,61 +721,44 @@ impl Scheduler {
                 }
             }
             self.running_pool = remain_running_tasks;
+            phase_timer.lap("running pool");
+
+            // skip tasks whose outputs are newer than their inputs before dispatching
+            debug!("Checking up to date tasks...");
+            let mut remain_waiting_tasks = VecDeque::new();
+            for mut task in std::mem::take(&mut self.waiting_queue) {
+                if task.get_executions() == 0 && task.is_ready() && task.is_up_to_date() {
+                    debug!("Task {} is up to date", task.get_name());
+                    task.set_status(TaskStatus::UpToDate);
+                    self.exit_task(task);
+                    pbar.inc(1);
+                } else {
+                    remain_waiting_tasks.push_back(task);
+                }
+            }
+            self.waiting_queue = remain_waiting_tasks;
+            phase_timer.lap("up to date check");
+
             // Secondly, Check System Status
             debug!("Checking System Status...");
-            let running_tasks = self.running_pool.len() + self.timeout_pool.len();
-            let workers = self.running_pool.len() + self.timeout_pool.len();
+            let running_tasks =
+                self.running_pool.len() + self.timeout_pool.len() + self.mem_stop_pool.len();
+            let workers = running_tasks;
+            // also when forcing workers, so OOM detection and autoscale see this tick
+            self.monitor.refresh(running_tasks);
             if workers < self.force_workers {
                 // if the force worker is larger than workers
                 // run tasks directly
-                if !self.waiting_queue.is_empty() {
-                    let mut task = self.waiting_queue.pop_front().unwrap();
-                    task.stdout_from_file(Path::new(&format!(
-                        "{}/{}.log",
-                        self.run_dir,
-                        task.get_name()
-                    )));
-                    task.stderr_from_file(Path::new(&format!(
-                        "{}/{}.err",
-                        self.run_dir,
-                        task.get_name()
-                    )));
-                    let ret = task.spawn();
-                    debug!("Start a new Task");
-                    if ret {
-                        self.running_pool.push(task);
-                    } else {
-                        warn!("Unable to spawn new child!");
-                        self.waiting_queue.push_back(task);
-                    }
-                }
+                pbar.inc(self.dispatch_up_to(workers, self.force_workers));
             } else {
-                match self.monitor.is_ok(running_tasks) {
+                let status = self.monitor.is_ok();
+                self.check_status_change(status);
+                match status {
                     SysStatus::Health => {
                         pbar.set_message("[System: Health]");
-                        // if system load is health, try to add a task to run,
-                        if !self.waiting_queue.is_empty() && workers < self.max_workers {
-                            let mut task = self.waiting_queue.pop_front().unwrap();
-                            task.stdout_from_file(Path::new(&format!(
-                                "{}/{}.log",
-                                self.run_dir,
-                                task.get_name()
-                            )));
-                            task.stderr_from_file(Path::new(&format!(
-                                "{}/{}.err",
-                                self.run_dir,
-                                task.get_name()
-                            )));
-                            let ret = task.spawn();
-                            debug!("Start a new Task");
-                            if ret {
-                                self.running_pool.push(task);
-                            } else {
-                                // failed to spawn a new process, back to wait
-                                warn!("Unable to spawn new child!");
-                                self.waiting_queue.push_back(task);
-                            }
-                        }
+                        // if system load is health, try to add tasks to run,
+                        pbar.inc(self.dispatch_up_to(workers, self.worker_cap()));
+                        self.ramp_up();
                     }
                     SysStatus::Normal => {
                         // do nothing,
@@ -226,29 +767,82 @@ impl Scheduler {
                     SysStatus::Bad => {
                         // try to stop a task
                         pbar.set_message("[System: Bad]");
-                        if workers > self.force_workers && !self.running_pool.is_empty() {
-                            let mut task = self.running_pool.pop().unwrap();
-                            task.stop().expect("Failed to kill task");
+                        // tasks younger than --min-run-before-kill are spared
+                        let victim = if workers > self.force_workers {
+                            self.running_pool.iter().rposition(|task| {
+                                task.running_time().as_secs_f64() >= self.min_run_before_kill
+                            })
+                        } else {
+                            None
+                        };
+                        if let Some(victim) = victim {
+                            let mut task = self.running_pool.remove(victim);
+                            if self.mem_kill_signal == Signal::Kill {
+                                task.stop().expect("Failed to kill task");
+                                task.set_status(TaskStatus::Waiting);
+                                self.waiting_queue.push_back(task);
+                            } else {
+                                // give the task a chance to clean up first
+                                let _ = task.signal(self.mem_kill_signal, true);
+                                task.reset_waiting_time();
+                                self.mem_stop_pool.push(task);
+                            }
+                        }
+                    }
+                }
+            }
+
+            phase_timer.lap("monitor and dispatch");
+
+            // requeue tasks stopped for memory, kill them after the grace period
+            debug!("Checking Memory Stop Pool...");
+            let mut remain_mem_stop_tasks = Vec::new();
+            for mut task in self.mem_stop_pool.drain(..) {
+                match task.try_wait() {
+                    Ok(Some(_)) => {
+                        task.set_status(TaskStatus::Waiting);
+                        self.waiting_queue.push_back(task);
+                    }
+                    Ok(None) => {
+                        if task.waiting_time().as_secs_f64() >= self.mem_kill_grace {
+                            debug!("Task Ignored Memory Stop Signal");
+                            let _ = task.stop();
+                            task.set_status(TaskStatus::Waiting);
                             self.waiting_queue.push_back(task);
+                        } else {
+                            remain_mem_stop_tasks.push(task);
                         }
                     }
+                    Err(e) => {
+                        // something going wrong, drop this task
+                        pbar.inc(1);
+                        warn!("Found Error: {}", e);
+                        continue;
+                    }
                 }
             }
+            self.mem_stop_pool = remain_mem_stop_tasks;
+            phase_timer.lap("memory stop pool");
 
             // cleanup force stop pool
             debug!("Checking Force Stop Pool...");
-            for mut task in self.force_stop_pool.drain(..) {
+            let mut remain_force_stop_tasks = Vec::new();
+            for (mut task, retries) in std::mem::take(&mut self.force_stop_pool) {
                 match task.try_wait() {
                     Ok(Some(_)) => {
                         // task finally stop itself
-                        self.exited_pool.push(task);
+                        self.exit_task(task);
                         debug!("Task Stop Itself");
                         pbar.inc(1);
                     }
+                    Ok(None) if retries > 0 => {
+                        // give it a few more ticks to finish its own cleanup
+                        remain_force_stop_tasks.push((task, retries - 1));
+                    }
                     Ok(None) => {
                         // we should stop the task forcely
                         let _ = task.stop();
-                        self.exited_pool.push(task);
+                        self.exit_task(task);
                         debug!("Task Stop Forcely");
                         pbar.inc(1);
                     }
@@ -260,20 +854,52 @@ impl Scheduler {
                     }
                 }
             }
-            // reinit this pool
-            self.force_stop_pool = Vec::new();
+            self.force_stop_pool = remain_force_stop_tasks;
+            phase_timer.lap("force stop pool");
 
             // Finally, check the timeout pool to waiting process exit itself or kill it.
             debug!("Checking Timeout Pool...");
             let mut remain_timeout_tasks = Vec::new();
-            for mut task in self.timeout_pool.drain(..) {
+            for (mut task, steps_sent) in std::mem::take(&mut self.timeout_pool) {
                 match task.try_wait() {
                     Ok(Some(_)) => {
                         // task stop itself
                         debug!("Task Stop Itself");
-                        self.exited_pool.push(task);
+                        self.exit_task(task);
                         pbar.inc(1);
                     }
+                    Ok(None) if !self.timeout_signals.is_empty() => {
+                        // the step sent last has had its delay, or nothing was sent yet
+                        let step_due = match steps_sent.checked_sub(1) {
+                            Some(last) => {
+                                task.waiting_time().as_secs_f64() >= self.timeout_signals[last].1
+                            }
+                            None => true,
+                        };
+                        if !step_due {
+                            remain_timeout_tasks.push((task, steps_sent));
+                            continue;
+                        }
+                        match self.timeout_signals.get(steps_sent) {
+                            Some((signal, _)) if *signal != Signal::Kill => {
+                                debug!(
+                                    "Send {} to timed out task {}",
+                                    signal_name(*signal),
+                                    task.get_name()
+                                );
+                                let _ = task.signal(*signal, true);
+                                task.reset_waiting_time();
+                                remain_timeout_tasks.push((task, steps_sent + 1));
+                            }
+                            // KILL, or every step went unheard
+                            _ => {
+                                let _ = task.stop();
+                                self.exit_task(task);
+                                debug!("Task Stop Forcely");
+                                pbar.inc(1);
+                            }
+                        }
+                    }
                     Ok(None) => {
                         let elapsed = task.waiting_time().as_secs_f64();
                         if elapsed >= self.timeout_wait {
@@ -281,11 +907,11 @@ impl Scheduler {
                             let _ = task.signal(rustix::process::Signal::Int, false);
                             let _ = task.signal(rustix::process::Signal::Alarm, true);
                             // move to force stop pool
-                            self.force_stop_pool.push(task);
+                            self.force_stop_pool.push((task, self.force_stop_retries));
                         } else {
                             // signal alarm to process
                             let _ = task.signal(rustix::process::Signal::Alarm, true);
-                            remain_timeout_tasks.push(task);
+                            remain_timeout_tasks.push((task, steps_sent));
                         }
                     }
                     Err(e) => {
@@ -298,6 +924,7 @@ impl Scheduler {
             }
 
             self.timeout_pool = remain_timeout_tasks;
+            phase_timer.lap("timeout pool");
 
             debug!("Time to Sleep");
             let tick_runing_time = tick_start.elapsed().as_millis();
@@ -311,22 +938,1358 @@ impl Scheduler {
             ));
             sleep(Duration::from_millis(tick_sleep_time as u64));
         }
+
+        // the batch is over, periodic tasks waiting for the next run are done,
+        // tasks left out by the limit are reported as not started
+        let mut remain_waiting_tasks = VecDeque::new();
+        for mut task in std::mem::take(&mut self.waiting_queue) {
+            if task.is_periodic() && task.get_executions() > 0 {
+                task.set_status(TaskStatus::Exited);
+            } else if task.get_executions() == 0
+                && (self.limit_reached() || self.draining_since.is_some())
+            {
+                task.set_status(TaskStatus::NotStarted);
+            } else {
+                remain_waiting_tasks.push_back(task);
+                continue;
+            }
+            self.exit_task(task);
+            pbar.inc(1);
+        }
+        self.waiting_queue = remain_waiting_tasks;
         pbar.finish();
-    }
 
-    pub fn write_report(&self) {
-        let log_path = format!("{}/cirno_task_pair.log", self.run_dir);
-        let mut file = std::fs::File::create(log_path).unwrap();
+        if self.phase_timing {
+            phase_timer.log();
+        }
+    }
 
+    fn batch_result(&self, wall_time: Duration) -> BatchResult {
+        let mut result = BatchResult {
+            wall_time,
+            cores: std::thread::available_parallelism()
+                .map(|cores| cores.get())
+                .unwrap_or(1),
+            ..Default::default()
+        };
         for task in &self.exited_pool {
-            let line = format!(
-                "{},{},{}\n",
+            let task_result = TaskResult::from(task);
+            result.task_time += task_result.running_time;
+            match task.get_status() {
+                TaskStatus::Timeout => result.timed_out.push(task_result),
+                TaskStatus::Killed | TaskStatus::OOMKilled => result.killed.push(task_result),
+                TaskStatus::NotStarted | TaskStatus::UpToDate => result.skipped.push(task_result),
+                _ if task.is_failed() => result.failed.push(task_result),
+                _ => result.succeeded.push(task_result),
+            }
+        }
+        // left behind by a stop request
+        for task in self.alive_tasks().chain(self.waiting_queue.iter()) {
+            let task_result = TaskResult::from(task);
+            result.task_time += task_result.running_time;
+            result.unfinished.push(task_result);
+        }
+        result
+    }
+
+    // every task done with for this batch ends here
+    fn exit_task(&mut self, mut task: Task) {
+        // the status stays how the task was stopped, leaked processes are noted aside
+        let survivors = task.get_kill_survivors();
+        if survivors > 0 {
+            let left = format!("KillIncomplete, {} processes left after kill", survivors);
+            let reason = match task.get_reason() {
+                Some(reason) => format!("{}, {}", reason, left),
+                None => left,
+            };
+            task.set_reason(&reason);
+        }
+        if self.stream_results {
+            let mut result = task_state(&task);
+            result["exit_code"] = json!(task.get_exit_code());
+            result["reason"] = json!(task.get_reason());
+            result["kill_survivors"] = json!(survivors);
+            // keep the line off the progress bars
+            self.multi_pbar.suspend(|| {
+                let mut stdout = std::io::stdout().lock();
+                let _ = writeln!(stdout, "{}", result);
+                let _ = stdout.flush();
+            });
+        }
+        self.exited_pool.push(task);
+    }
+
+    // if a waiting task still has to run in this batch,
+    // periodic tasks which have run and tasks left out by the limit do not
+    fn is_pending(&self, task: &Task) -> bool {
+        if task.get_executions() == 0 {
+            !self.limit_reached()
+        } else {
+            !task.is_periodic()
+        }
+    }
+
+    fn limit_reached(&self) -> bool {
+        self.limit.is_some_and(|limit| self.dispatched >= limit)
+    }
+
+    // max_workers, or with autoscale a cap between min and max workers,
+    // lower as the system gets close to its thresholds
+    fn worker_cap(&self) -> usize {
+        let cap = match self.min_workers {
+            Some(min_workers) => {
+                let min_workers = min_workers.min(self.max_workers);
+                let headroom = 1.0 - self.monitor.pressure();
+                let scale = ((self.max_workers - min_workers) as f64 * headroom).round() as usize;
+                debug!("Autoscale worker cap to {}", min_workers + scale);
+                min_workers + scale
+            }
+            None => self.max_workers,
+        };
+        match self.ramp_cap {
+            Some(ramp_cap) => cap.min(ramp_cap),
+            None => cap,
+        }
+    }
+
+    fn check_status_change(&mut self, status: SysStatus) {
+        let prev_status = match self.last_status.replace(status) {
+            Some(prev_status) if prev_status != status => prev_status,
+            _ => return,
+        };
+        info!("System status changed: {} -> {}", prev_status, status);
+
+        let cmd = match &self.on_status_change {
+            Some(cmd) => cmd,
+            None => return,
+        };
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .env("CIRNO_STATUS", status.to_string())
+            .env("CIRNO_PREV_STATUS", prev_status.to_string())
+            .stdin(Stdio::null())
+            .spawn();
+        match child {
+            // reap it aside, so a slow hook does not hold the tick
+            Ok(mut child) => {
+                std::thread::spawn(move || child.wait());
+            }
+            Err(e) => warn!("Failed to run --on-status-change: {}", e),
+        }
+    }
+
+    // a task dying of SIGKILL while memory is high was most likely taken by
+    // the OOM killer, the machine is overcommitted so run one worker less
+    fn check_oom_killed(&mut self, task: &Task, status: ExitStatus) {
+        if status.signal() != Some(Signal::Kill as i32) || !self.monitor.is_mem_high() {
+            return;
+        }
+        // forced workers run whatever the system says, going below is pointless
+        if self.max_workers > self.force_workers.max(1) {
+            self.max_workers -= 1;
+            warn!(
+                "Task {} looks OOM killed, reduce max workers to {}",
                 task.get_name(),
-                task.get_cmd(),
-                task.get_status()
+                self.max_workers
             );
+        }
+    }
 
-            let _ = file.write(line.as_bytes());
+    // stop taking new tasks and let the running ones finish, the second call stops the batch
+    fn drain(&mut self) {
+        if self.draining_since.is_some() {
+            warn!("Terminated again while draining, stop running tasks");
+            self.stop_flag.store(true, Ordering::Relaxed);
+            return;
         }
+        let alive = self.alive_tasks().count();
+        if self.drain_timeout > 0.0 {
+            info!(
+                "Draining, wait up to {:.1}s for {} running tasks, terminate again to stop them now",
+                self.drain_timeout, alive
+            );
+        } else {
+            info!(
+                "Draining, wait for {} running tasks, terminate again to stop them now",
+                alive
+            );
+        }
+        self.draining_since = Some(self.clock.now());
+    }
+
+    fn beat(&self) {
+        let heartbeat_file = match &self.heartbeat_file {
+            Some(heartbeat_file) => heartbeat_file,
+            None => return,
+        };
+        let res = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(heartbeat_file)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        if let Err(e) = res {
+            warn!("Failed to touch heartbeat file: {}", e);
+        }
+    }
+
+    // called on healthy ticks, the ramp ends once it reaches --workers
+    fn ramp_up(&mut self) {
+        if let Some(ramp_cap) = self.ramp_cap {
+            if ramp_cap + 1 >= self.max_workers {
+                debug!("Ramp up done");
+                self.ramp_cap = None;
+            } else {
+                debug!("Ramp up worker cap to {}", ramp_cap + 1);
+                self.ramp_cap = Some(ramp_cap + 1);
+            }
+        }
+    }
+
+    // spawn tasks until `cap` workers run, at most --max-spawns-per-tick of them,
+    // so a slow fork does not hold reaping and signals for too long
+    // return how many tasks were rejected instead, they are done for the progress bar
+    fn dispatch_up_to(&mut self, workers: usize, cap: usize) -> u64 {
+        let mut spawned = 0;
+        let mut rejected = 0;
+        while workers + spawned < cap && spawned < self.max_spawns_per_tick {
+            match self.dispatch() {
+                Dispatch::Spawned => spawned += 1,
+                Dispatch::Rejected => rejected += 1,
+                Dispatch::Nothing => break,
+            }
+        }
+        rejected
+    }
+
+    // pick the waiting task allowed to run now with the highest priority and spawn it,
+    // the earlier one in queue wins on a tie
+    fn dispatch(&mut self) -> Dispatch {
+        // shutdown may be requested in the middle of a tick
+        if self.stop_flag.load(Ordering::Relaxed) {
+            debug!("Stop requested, skip spawning");
+            return Dispatch::Nothing;
+        }
+        if self.paused || self.draining_since.is_some() {
+            return Dispatch::Nothing;
+        }
+        // nothing runs next to an exclusive task
+        if self
+            .alive_tasks()
+            .any(|task| task.has_label(EXCLUSIVE_LABEL))
+        {
+            return Dispatch::Nothing;
+        }
+        let index = {
+            let running_mutexes = self.running_mutex_counts();
+            let heavy_full = self
+                .heavy_workers
+                .is_some_and(|heavy_workers| self.running_heavy_count() >= heavy_workers);
+            let mut best: Option<(usize, f64)> = None;
+            for (index, task) in self.waiting_queue.iter().enumerate() {
+                if !task.is_ready() || !Self::is_eligible(task, &running_mutexes) {
+                    continue;
+                }
+                // only tasks already started may run again after the limit
+                if task.get_executions() == 0 && self.limit_reached() {
+                    continue;
+                }
+                // light tasks still run while heavy ones wait for their cap
+                if heavy_full && task.has_label(HEAVY_LABEL) {
+                    continue;
+                }
+                if self.exclusive_hold && !task.has_label(EXCLUSIVE_LABEL) {
+                    continue;
+                }
+                let priority = task.effective_priority(self.priority_aging);
+                if best.is_none_or(|(_, best_priority)| priority > best_priority) {
+                    best = Some((index, priority));
+                }
+            }
+            best.map(|(index, _)| index)
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                self.exclusive_hold = false;
+                return Dispatch::Nothing;
+            }
+        };
+        if self.waiting_queue[index].has_label(EXCLUSIVE_LABEL) {
+            // wait for the running ones to drain
+            if self.alive_tasks().next().is_some() {
+                if !self.exclusive_hold {
+                    info!(
+                        "Hold new tasks until running ones finish, {} runs alone",
+                        self.waiting_queue[index].get_name()
+                    );
+                    self.exclusive_hold = true;
+                }
+                return Dispatch::Nothing;
+            }
+            self.exclusive_hold = false;
+        }
+        let gpus = if self.gpus_per_task > 0 {
+            match self.pick_gpus() {
+                Some(gpus) => Some(gpus),
+                None => return Dispatch::Nothing,
+            }
+        } else {
+            None
+        };
+        let mut task = self.waiting_queue.remove(index).unwrap();
+        let is_first_run = task.get_executions() == 0;
+        if self.affinity_slots.is_some() {
+            let slot = self.pick_cpu_slot();
+            match &slot {
+                Some((slot, cpus)) => {
+                    debug!("Pin task {} to slot {} {:?}", task.get_name(), slot, cpus)
+                }
+                // more tasks alive than slots, like ones left in the timeout pool
+                None => debug!("No free cpu slot, task {} runs unpinned", task.get_name()),
+            }
+            task.pin_cpus(slot);
+        }
+        if let Some(gpus) = gpus {
+            debug!("Give gpus {:?} to task {}", gpus, task.get_name());
+            let vendor = self.monitor.get_gpu_vendor();
+            task.assign_gpus(gpus, vendor.visible_devices_env());
+            // CUDA numbers cards fastest first, nvidia-smi by pci bus,
+            // set once as `with_env` keeps it for the retries
+            if vendor == GpuVendor::Nvidia && is_first_run {
+                task.with_env("CUDA_DEVICE_ORDER", "PCI_BUS_ID");
+            }
+        }
+        if !task.is_capturing_output() {
+            let to_journal = match self.output_mode {
+                OutputMode::File => false,
+                OutputMode::Journal => match task.output_to_journal() {
+                    Ok(_) => true,
+                    Err(e) => {
+                        warn!("Failed to start logger, fall back to log files: {}", e);
+                        false
+                    }
+                },
+            };
+            if !to_journal {
+                task.stdout_from_file(&self.log_path(task.get_name(), "log"));
+                task.stderr_from_file(&self.log_path(task.get_name(), "err"));
+            }
+        }
+        debug!("Start a new Task");
+        if task.spawn() {
+            if is_first_run {
+                self.dispatched += 1;
+            }
+            self.running_pool.push(task);
+            Dispatch::Spawned
+        } else if let Some(dir) = task.get_current_dir().filter(|dir| !dir.is_dir()) {
+            // waiting does not make the directory appear, unlike for a busy system
+            let reason = format!("working directory {} does not exist", dir.display());
+            warn!("Failed to start {}: {}", task.get_name(), reason);
+            task.set_status(TaskStatus::Rejected);
+            task.set_reason(&reason);
+            self.exit_task(task);
+            Dispatch::Rejected
+        } else {
+            // failed to spawn a new process, back to wait
+            warn!("Unable to spawn new child!");
+            self.waiting_queue.push_back(task);
+            Dispatch::Nothing
+        }
+    }
+
+    // `run_dir/<name>.<ext>`, or `run_dir/<shard>/<name>.<ext>` with log sharding,
+    // the name is only sanitized here, reports keep the original one
+    fn log_path(&self, name: &str, ext: &str) -> PathBuf {
+        let name = &sanitize_file_name(name);
+        let mut path = PathBuf::from(&self.run_dir);
+        if self.log_shard > 0 {
+            // stable across runs so logs of a task always land in the same shard
+            let hash = fnv1a(name.as_bytes());
+            path.push((hash % self.log_shard as u64).to_string());
+        }
+        path.push(format!("{}.{}", name, ext));
+        path
+    }
+
+    // tasks still holding their process, which may hold mutex labels
+    fn alive_tasks(&self) -> impl Iterator<Item = &Task> {
+        self.running_pool
+            .iter()
+            .chain(self.timeout_pool.iter().map(|(task, _)| task))
+            .chain(self.force_stop_pool.iter().map(|(task, _)| task))
+            .chain(self.mem_stop_pool.iter())
+    }
+
+    fn running_mutex_counts(&self) -> HashMap<&str, usize> {
+        let mut counts = HashMap::new();
+        for task in self.alive_tasks() {
+            for label in task.get_mutexes() {
+                *counts.entry(label.as_str()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    // gpus held by an alive task, requeued and exited tasks hold none
+    fn occupied_gpus(&self) -> HashSet<u32> {
+        self.alive_tasks()
+            .flat_map(|task| task.get_gpus().iter().copied())
+            .collect()
+    }
+
+    // --gpus-per-task free cards nobody holds, lowest index first
+    fn pick_gpus(&mut self) -> Option<Vec<u32>> {
+        let mut free_gpus = self.monitor.free_gpus();
+        let occupied = self.occupied_gpus();
+        free_gpus.retain(|gpu| !occupied.contains(gpu));
+        if free_gpus.len() < self.gpus_per_task {
+            debug!(
+                "{} free gpus, {} needed, hold new tasks",
+                free_gpus.len(),
+                self.gpus_per_task
+            );
+            return None;
+        }
+        free_gpus.sort_unstable();
+        free_gpus.truncate(self.gpus_per_task);
+        Some(free_gpus)
+    }
+
+    // the lowest slot no alive task holds, with its cpus
+    fn pick_cpu_slot(&self) -> Option<(usize, Vec<usize>)> {
+        let slots = self.affinity_slots.as_ref()?;
+        let occupied: HashSet<usize> = self
+            .alive_tasks()
+            .filter_map(|task| task.get_cpu_slot())
+            .collect();
+        let slot = (0..slots.len()).find(|slot| !occupied.contains(slot))?;
+        Some((slot, slots[slot].clone()))
+    }
+
+    fn running_heavy_count(&self) -> usize {
+        self.alive_tasks()
+            .filter(|task| task.has_label(HEAVY_LABEL))
+            .count()
+    }
+
+    fn is_eligible(task: &Task, running_mutexes: &HashMap<&str, usize>) -> bool {
+        // never run two tasks sharing a mutex label at the same time
+        task.get_mutexes()
+            .iter()
+            .all(|label| !running_mutexes.contains_key(label.as_str()))
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.exit_code_mode {
+            ExitCodeMode::Zero => 0,
+            ExitCodeMode::Bits => {
+                let mut code = 0;
+                for task in &self.exited_pool {
+                    match task.get_status() {
+                        TaskStatus::Timeout => code |= 2,
+                        _ if task.is_failed() => code |= 1,
+                        _ => {}
+                    }
+                }
+                code
+            }
+        }
+    }
+
+    // pool sizes and the running tasks, much smaller than `dump_state`
+    fn status_snapshot(&self) -> serde_json::Value {
+        json!({
+            "waiting": self.waiting_queue.len(),
+            "running": self.running_pool.len(),
+            "timeout": self.timeout_pool.len() + self.force_stop_pool.len(),
+            "mem_stop": self.mem_stop_pool.len(),
+            "exited": self.exited_pool.len(),
+            "workers": self.max_workers,
+            "paused": self.paused,
+            "draining": self.draining_since.is_some(),
+            "running_tasks": self.running_pool.iter().map(|task| json!({
+                "name": task.get_name(),
+                "cmd": task.get_cmd(),
+                "running_time": task.running_time().as_secs_f64(),
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    // write every pool and the monitor readings as json, for debugging
+    pub fn dump_state(&self) {
+        let readings = self.monitor.get_readings();
+
+        let state = json!({
+            "workers": {
+                "max_workers": self.max_workers,
+                "min_workers": self.min_workers,
+                "worker_cap": self.worker_cap(),
+                "force_workers": self.force_workers,
+                "heavy_workers": self.heavy_workers,
+                "ramp_cap": self.ramp_cap,
+            },
+            "monitor": {
+                "load_avg": readings.load_avg,
+                "swap_usage": readings.swap_usage,
+                "mem_used": readings.mem_used,
+                "per_task_mem": readings.per_task_mem,
+                "os_per_task_mem": readings.os_per_task_mem,
+                "disk_util": readings.disk_util,
+                "high_mem_thres": self.monitor.get_high_mem_thres(),
+                "low_mem_thres": self.monitor.get_low_mem_thres(),
+            },
+            "waiting_queue": self.waiting_queue.iter().map(task_state).collect::<Vec<_>>(),
+            "running_pool": self.running_pool.iter().map(task_state).collect::<Vec<_>>(),
+            "timeout_pool": self.timeout_pool.iter().map(|(task, _)| task_state(task)).collect::<Vec<_>>(),
+            "force_stop_pool": self.force_stop_pool.iter().map(|(task, _)| task_state(task)).collect::<Vec<_>>(),
+            "mem_stop_pool": self.mem_stop_pool.iter().map(task_state).collect::<Vec<_>>(),
+            "exited_pool": self.exited_pool.iter().map(task_state).collect::<Vec<_>>(),
+        });
+
+        let dump_path = format!("{}/cirno_dump.json", self.run_dir);
+        match serde_json::to_string_pretty(&state) {
+            Ok(dump) => match std::fs::write(&dump_path, dump) {
+                Ok(_) => info!("Dumped scheduler state to {}", dump_path),
+                Err(e) => warn!("Failed to dump scheduler state: {}", e),
+            },
+            Err(e) => warn!("Failed to dump scheduler state: {}", e),
+        }
+    }
+
+    fn report_path(&self) -> String {
+        match self.report_format {
+            ReportFormat::Csv => format!("{}/cirno_task_pair.log", self.run_dir),
+            ReportFormat::Json => format!("{}/cirno_task_pair.json", self.run_dir),
+        }
+    }
+
+    // drop waiting tasks a previous report of run_dir has as `Exited` with code 0,
+    // by name or by command, their entries are kept in the new report
+    pub fn resume(&mut self, by_name: bool) {
+        let report_path = self.report_path();
+        // a report cut off while written may end within a char
+        let contents = match std::fs::read(&report_path) {
+            Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No report {} to resume from", report_path);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to read report {}: {}", report_path, e);
+                return;
+            }
+        };
+        let entries = match self.report_format {
+            ReportFormat::Csv => parse_csv_report(&contents),
+            ReportFormat::Json => parse_json_report(&contents),
+        };
+        let mut done = HashSet::new();
+        for entry in entries {
+            if entry.status == "Exited" && entry.exit_code == Some(0) {
+                done.insert(if by_name { entry.name } else { entry.cmd });
+                self.resumed_report.push(entry.raw);
+            }
+        }
+
+        let before = self.waiting_queue.len();
+        self.waiting_queue.retain(|task| {
+            if by_name {
+                !done.contains(task.get_name())
+            } else {
+                !done.iter().any(|cmd| task.is_cmd(cmd))
+            }
+        });
+        info!(
+            "Resume from {}: skip {} tasks done before",
+            report_path,
+            before - self.waiting_queue.len()
+        );
+    }
+
+    // the exited pool only grows, so only tasks exited since the last call are appended,
+    // in input order or as json the file is rewritten instead
+    pub fn write_report(&mut self) {
+        let log_path = self.report_path();
+        let append =
+            self.report_format == ReportFormat::Csv && self.report_order == ReportOrder::Completion;
+        let (file, written, fresh) = match self.report_written {
+            Some(written) if written == self.exited_pool.len() => return,
+            Some(written) if append => {
+                match std::fs::OpenOptions::new().append(true).open(&log_path) {
+                    // removed while running, write it all again
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        (std::fs::File::create(&log_path), 0, true)
+                    }
+                    file => (file, written, false),
+                }
+            }
+            _ => (std::fs::File::create(&log_path), 0, true),
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Failed to open report {}: {}", log_path, e);
+                return;
+            }
+        };
+        let mut tasks: Vec<&Task> = self.exited_pool[written..].iter().collect();
+        if self.report_order == ReportOrder::Input {
+            tasks.sort_by_key(|task| task.get_submit_index());
+        }
+        // a fresh file starts with what was resumed
+        let resumed = if fresh { &self.resumed_report[..] } else { &[] };
+
+        let mut writer = BufWriter::new(file);
+        let result = match self.report_format {
+            ReportFormat::Csv => resumed
+                .iter()
+                .try_for_each(|line| writeln!(writer, "{}", line))
+                .and_then(|_| {
+                    tasks.into_iter().try_for_each(|task| {
+                        writeln!(
+                            writer,
+                            "{},{},{},{},{},{},{},{:.3},{}",
+                            csv_field(task.get_name()),
+                            csv_field(&task.get_cmd()),
+                            task.get_status(),
+                            task.get_exit_code()
+                                .map(|code| code.to_string())
+                                .unwrap_or_default(),
+                            task.get_executions(),
+                            format_unix_time(task.first_start_unix_time()),
+                            format_unix_time(task.end_unix_time()),
+                            task.total_running_time().as_secs_f64(),
+                            csv_field(task.get_reason().unwrap_or_default())
+                        )
+                    })
+                }),
+            ReportFormat::Json => {
+                // same fields as the csv columns, one object per line
+                let report: Vec<String> = resumed
+                    .iter()
+                    .cloned()
+                    .chain(tasks.into_iter().map(|task| {
+                        json!({
+                            "name": task.get_name(),
+                            "cmd": task.get_cmd(),
+                            "status": task.get_status().to_string(),
+                            "exit_code": task.get_exit_code(),
+                            "executions": task.get_executions(),
+                            "start": task.first_start_unix_time().map(|time| time.as_secs_f64()),
+                            "end": task.end_unix_time().map(|time| time.as_secs_f64()),
+                            "duration": task.total_running_time().as_secs_f64(),
+                            "reason": task.get_reason(),
+                        })
+                        .to_string()
+                    }))
+                    .collect();
+                writeln!(writer, "[\n{}\n]", report.join(",\n"))
+            }
+        };
+        match result.and_then(|_| writer.flush()) {
+            Ok(_) => self.report_written = Some(self.exited_pool.len()),
+            Err(e) => warn!("Failed to write report {}: {}", log_path, e),
+        }
+    }
+}
+
+// a task of a previous report, `raw` is it as written
+struct ReportEntry {
+    name: String,
+    cmd: String,
+    status: String,
+    exit_code: Option<i32>,
+    raw: String,
+}
+
+// `name,cmd,status,exit_code,executions,start,end,duration,reason` with fields quoted
+// by `csv_field`, rows of other layouts are skipped, reports written before fields were
+// quoted may still hold commas in the command
+fn parse_csv_report(contents: &str) -> Vec<ReportEntry> {
+    let mut entries = Vec::new();
+    for (fields, raw) in csv_records(contents) {
+        let (name, cmd, status, exit_code) = match &fields[..] {
+            [name, cmd, status, exit_code, _, _, _, _, _] => {
+                (name.clone(), cmd.clone(), status, exit_code)
+            }
+            [name, .., status, exit_code, _, _, _, _, _] if !raw.contains('"') => {
+                let cmd = fields[1..fields.len() - 7].join(",");
+                (name.clone(), cmd, status, exit_code)
+            }
+            _ => continue,
+        };
+        entries.push(ReportEntry {
+            name,
+            cmd,
+            status: status.clone(),
+            exit_code: exit_code.parse().ok(),
+            raw: raw.to_string(),
+        });
+    }
+    entries
+}
+
+// a report field as is, or in `"` with `"` doubled if it holds a separator,
+// a quote or a line break
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// the fields of each csv record with its text, a quoted field may span lines,
+// a record left in an open quote by a cut off file is dropped
+fn csv_records(contents: &str) -> Vec<(Vec<String>, &str)> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    let mut chars = contents.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek().is_some_and(|(_, next)| *next == '"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            '\n' if !in_quotes => {
+                fields.push(std::mem::take(&mut field));
+                let raw = contents[start..pos].trim_end_matches('\r');
+                records.push((std::mem::take(&mut fields), raw));
+                start = pos + 1;
+            }
+            c => field.push(c),
+        }
+    }
+    if start < contents.len() && !in_quotes {
+        fields.push(field);
+        records.push((fields, &contents[start..]));
+    }
+    records
+}
+
+fn parse_json_report(contents: &str) -> Vec<ReportEntry> {
+    let report: Vec<serde_json::Value> = match serde_json::from_str(contents) {
+        Ok(report) => report,
+        Err(e) => {
+            warn!("Failed to parse json report: {}", e);
+            return Vec::new();
+        }
+    };
+    report
+        .into_iter()
+        .map(|entry| ReportEntry {
+            name: entry["name"].as_str().unwrap_or_default().to_string(),
+            cmd: entry["cmd"].as_str().unwrap_or_default().to_string(),
+            status: entry["status"].as_str().unwrap_or_default().to_string(),
+            exit_code: entry["exit_code"].as_i64().map(|code| code as i32),
+            raw: entry.to_string(),
+        })
+        .collect()
+}
+
+// keep `[A-Za-z0-9._-]`, so a task name can not escape the run dir or
+// produce names the filesystem rejects
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name,
+    }
+}
+
+// time spent in each phase of the run loop, summed over all ticks
+#[derive(Debug, Default)]
+struct PhaseTimer {
+    phases: Vec<(&'static str, Duration)>,
+    last: Option<Instant>,
+}
+
+impl PhaseTimer {
+    fn start(&mut self) {
+        self.last = Some(Instant::now());
+    }
+
+    // add the time since the last lap to `phase`
+    fn lap(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        let spent = match self.last.replace(now) {
+            Some(last) => now.saturating_duration_since(last),
+            None => return,
+        };
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += spent,
+            None => self.phases.push((phase, spent)),
+        }
+    }
+
+    fn log(&self) {
+        let total: Duration = self.phases.iter().map(|(_, spent)| *spent).sum();
+        info!("Time per phase, {:.3}s in total:", total.as_secs_f64());
+        for (phase, spent) in &self.phases {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                spent.as_secs_f64() / total.as_secs_f64()
+            };
+            info!(
+                "  {:<20} {:>10.3}s {:>5.1}%",
+                phase,
+                spent.as_secs_f64(),
+                share * 100.0
+            );
+        }
+    }
+}
+
+// what `dispatch` did with the task it picked
+enum Dispatch {
+    Spawned,
+    // it can never start, and went to the exited pool
+    Rejected,
+    // no task can start now
+    Nothing,
+}
+
+// how a task ended, for library users of `Scheduler::start`
+#[derive(Debug, Clone)]
+pub struct TaskResult {
+    pub name: String,
+    pub cmd: String,
+    pub status: TaskStatus,
+    // negated signal number if it was killed by one, like in the report
+    pub exit_code: Option<i32>,
+    pub reason: Option<String>,
+    // processes left alive after the last run was killed
+    pub kill_survivors: usize,
+    pub executions: usize,
+    // summed over all executions
+    pub running_time: Duration,
+    // from submission to the first start
+    pub queued_time: Duration,
+}
+
+impl From<&Task> for TaskResult {
+    fn from(task: &Task) -> Self {
+        TaskResult {
+            name: task.get_name().to_string(),
+            cmd: task.get_cmd(),
+            status: task.get_status(),
+            exit_code: task.get_exit_code(),
+            reason: task.get_reason().map(str::to_string),
+            kill_survivors: task.get_kill_survivors(),
+            executions: task.get_executions(),
+            running_time: task.total_running_time(),
+            queued_time: task.queued_time(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BatchResult {
+    pub succeeded: Vec<TaskResult>,
+    pub failed: Vec<TaskResult>,
+    pub timed_out: Vec<TaskResult>,
+    pub killed: Vec<TaskResult>,
+    // not started by the limit, or up to date
+    pub skipped: Vec<TaskResult>,
+    // still waiting or running when the batch was stopped
+    pub unfinished: Vec<TaskResult>,
+
+    pub wall_time: Duration,
+    pub task_time: Duration,
+    pub cores: usize,
+}
+
+impl BatchResult {
+    pub fn total(&self) -> usize {
+        self.succeeded.len()
+            + self.failed.len()
+            + self.timed_out.len()
+            + self.killed.len()
+            + self.skipped.len()
+            + self.unfinished.len()
+    }
+
+    // task running time over what the machine could have run in the batch,
+    // tells whether more workers would fit
+    pub fn utilization(&self) -> f64 {
+        let capacity = self.wall_time.as_secs_f64() * self.cores as f64;
+        if capacity > 0.0 {
+            self.task_time.as_secs_f64() / capacity
+        } else {
+            0.0
+        }
+    }
+}
+
+fn task_state(task: &Task) -> serde_json::Value {
+    json!({
+        "name": task.get_name(),
+        "cmd": task.get_cmd(),
+        "status": task.get_status().to_string(),
+        "running_time": task.running_time().as_secs_f64(),
+        "waiting_time": task.waiting_time().as_secs_f64(),
+        "executions": task.get_executions(),
+    })
+}
+
+// an equal range of `cpus` for each of `workers` slots, slots get at least a cpu each
+fn split_cpu_slots(cpus: &[usize], workers: usize) -> Vec<Vec<usize>> {
+    let slots = workers.min(cpus.len()).max(1);
+    let per_slot = cpus.len() / slots;
+    cpus.chunks(per_slot.max(1))
+        .take(slots)
+        .map(|slot| slot.to_vec())
+        .collect()
+}
+
+// seconds since the epoch with millis for the report, empty if unknown
+fn format_unix_time(time: Option<Duration>) -> String {
+    time.map(|time| format!("{:.3}", time.as_secs_f64()))
+        .unwrap_or_default()
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::MockClock;
+
+    // a scheduler with its own run dir, ticking fast and not waiting for free resources,
+    // 4 workers unless `args` sets them
+    fn scheduler(name: &str, args: &[&str]) -> Scheduler {
+        let run_dir = std::env::temp_dir().join(format!("cirno-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&run_dir);
+        let run_dir = run_dir.to_str().unwrap().to_string();
+        let workers: &[&str] = if args.contains(&"-w") {
+            &[]
+        } else {
+            &["-w", "4", "-f", "4"]
+        };
+        let argv = ["cirno", "--tick-rate", "50", "--run-dir", &run_dir]
+            .into_iter()
+            .chain(workers.iter().copied())
+            .chain(args.iter().copied())
+            .chain(["tasks.txt"])
+            .map(str::to_string);
+        Scheduler::new(&Args::parse_with_config(argv).unwrap())
+    }
+
+    fn submit(scheduler: &mut Scheduler, line: &str) {
+        scheduler.submit(Task::new(line, None).unwrap());
+    }
+
+    // run the batch on a mock clock moving `step` every few millis, so long
+    // timeouts pass in a moment, return the result and the real time taken
+    fn start_with_mock_clock(scheduler: &mut Scheduler, step: Duration) -> (BatchResult, Duration) {
+        let clock = Arc::new(MockClock::new());
+        scheduler.set_clock(clock.clone());
+        let done = Arc::new(AtomicBool::new(false));
+        let ticker = {
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    clock.advance(step);
+                    sleep(Duration::from_millis(5));
+                }
+            })
+        };
+        let start = Instant::now();
+        let result = scheduler.start();
+        done.store(true, Ordering::Relaxed);
+        ticker.join().unwrap();
+        (result, start.elapsed())
+    }
+
+    #[test]
+    fn missing_cwd_is_rejected() {
+        let mut scheduler = scheduler("missing-cwd", &[]);
+        let marker = std::env::temp_dir().join(format!("cirno-cd-{}", std::process::id()));
+        let cmd = format!("cd:/nonexistent/cirno touch {}", marker.display());
+        submit(&mut scheduler, &cmd);
+        let result = scheduler.start();
+
+        assert_eq!(result.failed.len(), 1);
+        let task = &scheduler.exited_pool[0];
+        assert!(matches!(task.get_status(), TaskStatus::Rejected));
+        assert_eq!(task.get_executions(), 0);
+        assert!(task.get_reason().unwrap().contains("/nonexistent/cirno"));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn signal_shows_as_negative_exit_code() {
+        let mut scheduler = scheduler("signal-exit", &[]);
+        submit(&mut scheduler, "sh -c 'kill -9 $$'");
+        submit(&mut scheduler, "sh -c 'exit 3'");
+        let result = scheduler.start();
+
+        let mut codes: Vec<_> = result.failed.iter().map(|task| task.exit_code).collect();
+        codes.sort();
+        assert_eq!(codes, [Some(-9), Some(3)]);
+    }
+
+    #[test]
+    fn direct_kill_keeps_timeout_status() {
+        let mut scheduler = scheduler(
+            "direct-kill",
+            &[
+                "-t",
+                "0.2",
+                "--timeout-wait",
+                "0.2",
+                "--kill-scope",
+                "direct",
+                "--exit-code-mode",
+                "bits",
+            ],
+        );
+        // only the shell is killed, the sleep it waits for is left alone on purpose
+        submit(
+            &mut scheduler,
+            "sh -c 'trap \"\" INT TERM ALRM; sleep 1; sleep 1'",
+        );
+        let result = scheduler.start();
+
+        assert_eq!(result.timed_out.len(), 1);
+        assert_eq!(result.timed_out[0].kill_survivors, 0);
+        assert_eq!(result.timed_out[0].reason, None);
+        assert_eq!(scheduler.exit_code(), 2);
+    }
+
+    #[test]
+    fn retries_are_counted_once() {
+        let mut scheduler = scheduler(
+            "retries",
+            &["--max-retries", "2", "--min-healthy-runtime", "0.5"],
+        );
+        let runs = std::env::temp_dir().join(format!("cirno-runs-{}", std::process::id()));
+        let _ = std::fs::remove_file(&runs);
+        submit(
+            &mut scheduler,
+            &format!("sh -c 'echo run >> {}; exit 3'", runs.display()),
+        );
+        // every run lasts a tick, many mock seconds, so none of them failed fast
+        let (result, _) = start_with_mock_clock(&mut scheduler, Duration::from_secs(1));
+
+        assert_eq!(std::fs::read_to_string(&runs).unwrap().lines().count(), 3);
+        assert!(result.succeeded.is_empty());
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].executions, 3);
+        assert_eq!(result.failed[0].exit_code, Some(3));
+        assert_eq!(scheduler.exited_pool.len(), 1);
+        assert!(matches!(
+            scheduler.exited_pool[0].get_status(),
+            TaskStatus::Exited
+        ));
+
+        scheduler.write_report();
+        let report = std::fs::read_to_string(scheduler.report_path()).unwrap();
+        let records = csv_records(&report);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0[2], "Exited");
+        assert_eq!(records[0].0[4], "3");
+    }
+
+    #[test]
+    fn retries_run_before_failed_fast() {
+        let mut scheduler = scheduler(
+            "retries-fast",
+            &["--max-retries", "2", "--min-healthy-runtime", "60"],
+        );
+        submit(&mut scheduler, "sh -c 'exit 3'");
+        let result = scheduler.start();
+
+        // only the last run is judged, the retries are used up first
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].executions, 3);
+        let task = &scheduler.exited_pool[0];
+        assert!(matches!(task.get_status(), TaskStatus::FailedFast));
+        assert!(task.get_reason().unwrap().starts_with("failed after"));
+    }
+
+    #[test]
+    fn mock_clock_drives_timeout() {
+        let mut scheduler = scheduler("mock-timeout", &["-t", "60"]);
+        submit(&mut scheduler, "sleep 30");
+        let (result, real_time) = start_with_mock_clock(&mut scheduler, Duration::from_secs(1));
+
+        assert_eq!(result.timed_out.len(), 1);
+        assert!(result.wall_time >= Duration::from_secs(60));
+        assert!(real_time < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn mock_clock_drives_timeout_wait() {
+        let mut scheduler = scheduler("mock-timeout-wait", &["-t", "10", "--timeout-wait", "60"]);
+        // deaf to every signal before the kill
+        submit(
+            &mut scheduler,
+            "sh -c 'trap \"\" INT TERM ALRM; while :; do sleep 0.05; done'",
+        );
+        let (result, real_time) = start_with_mock_clock(&mut scheduler, Duration::from_secs(1));
+
+        assert_eq!(result.timed_out.len(), 1);
+        assert_eq!(result.timed_out[0].exit_code, Some(-9));
+        assert!(result.wall_time >= Duration::from_secs(70));
+        assert!(real_time < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn mock_clock_drives_escalation() {
+        let mut scheduler = scheduler(
+            "mock-escalation",
+            &["-t", "10", "--timeout-signal", "USR1:30,TERM:30"],
+        );
+        // only the second step is heard
+        submit(
+            &mut scheduler,
+            "sh -c 'trap \"\" USR1; trap \"exit 7\" TERM; while :; do sleep 0.05; done'",
+        );
+        let (result, real_time) = start_with_mock_clock(&mut scheduler, Duration::from_secs(1));
+
+        assert_eq!(result.timed_out.len(), 1);
+        assert_eq!(result.timed_out[0].exit_code, Some(7));
+        assert!(result.wall_time >= Duration::from_secs(40));
+        assert!(real_time < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn resume_from_truncated_report() {
+        let mut scheduler = scheduler("resume-truncated", &[]);
+        for name in ["a", "b", "c", "d"] {
+            submit(&mut scheduler, &format!("echo {}", name));
+        }
+        let report = "\
+a,echo a,Exited,0,1,1.000,2.000,1.000,
+b,echo b,Exited,1,1,1.000,2.000,1.000,
+c,echo c,Exited,0,1,1.000,2.000,1.000,
+d,echo d,Exited,0,1,1.0";
+        let report = [report.as_bytes(), "é".as_bytes().split_last().unwrap().1].concat();
+        std::fs::write(scheduler.report_path(), report).unwrap();
+        scheduler.resume(false);
+
+        let left: Vec<String> = scheduler.waiting_queue.iter().map(Task::get_cmd).collect();
+        assert_eq!(left, ["echo b", "echo d"]);
+        assert_eq!(scheduler.resumed_report.len(), 2);
+    }
+
+    #[test]
+    fn task_mem_limit_caps_allocation() {
+        let mut scheduler = scheduler("task-mem-limit", &["--task-mem-limit", "128M"]);
+        // doubling a string up to 512M, and up to 1M
+        let alloc = |doublings: usize| {
+            format!(
+                "awk 'BEGIN {{ s = \"x\"; for (i = 0; i < {}; i++) s = s s }}'",
+                doublings
+            )
+        };
+        submit(&mut scheduler, &alloc(29));
+        submit(&mut scheduler, &alloc(20));
+        let result = scheduler.start();
+
+        assert_eq!(result.failed.len(), 1);
+        assert!(result.failed[0].cmd.contains("< 29"));
+        assert_ne!(result.failed[0].exit_code, Some(0));
+        assert_eq!(result.succeeded.len(), 1);
+        assert!(result.succeeded[0].cmd.contains("< 20"));
+    }
+
+    #[test]
+    fn heavy_and_light_tasks_share_workers() {
+        let mut scheduler = scheduler(
+            "heavy-workers",
+            &["-w", "3", "-f", "3", "--heavy-workers", "1"],
+        );
+        for _ in 0..3 {
+            submit(&mut scheduler, "label:heavy sleep 0.3");
+        }
+        for _ in 0..6 {
+            submit(&mut scheduler, "sleep 0.3");
+        }
+        let result = scheduler.start();
+        assert_eq!(result.succeeded.len(), 9);
+
+        let runs = |heavy: bool| {
+            let mut runs: Vec<(f64, f64)> = scheduler
+                .exited_pool
+                .iter()
+                .filter(|task| task.has_label(HEAVY_LABEL) == heavy)
+                .map(|task| {
+                    let start = task.first_start_unix_time().unwrap().as_secs_f64();
+                    (start, task.end_unix_time().unwrap().as_secs_f64())
+                })
+                .collect();
+            runs.sort_by(|a, b| a.0.total_cmp(&b.0));
+            runs
+        };
+        let (heavy, light) = (runs(true), runs(false));
+        // one heavy task at a time
+        assert!(heavy.windows(2).all(|pair| pair[1].0 >= pair[0].1));
+        // light tasks use the other workers while heavy ones wait
+        assert!(light[0].0 < heavy[0].1);
+        assert!(light[1].0 < heavy[0].1);
+        // and the queued light tasks do not keep heavy ones out
+        let light_end = light.iter().map(|run| run.1).fold(0.0, f64::max);
+        assert!(heavy.iter().all(|run| run.0 < light_end));
+    }
+
+    #[test]
+    fn oom_kills_keep_forced_workers() {
+        let mut scheduler = scheduler(
+            "oom-workers",
+            &["-w", "4", "-f", "2", "--low-mem-thres", "0"],
+        );
+        // readings come without asking if the system is ok
+        scheduler.monitor.refresh(0);
+        assert!(scheduler.monitor.is_mem_high());
+
+        let task = Task::new("true", None).unwrap();
+        for _ in 0..4 {
+            scheduler.check_oom_killed(&task, ExitStatus::from_raw(Signal::Kill as i32));
+        }
+        assert_eq!(scheduler.max_workers, 2);
+    }
+
+    #[test]
+    fn cpu_slots_split() {
+        let cpus: Vec<usize> = (0..8).collect();
+        assert_eq!(split_cpu_slots(&cpus, 4), [[0, 1], [2, 3], [4, 5], [6, 7]]);
+        assert_eq!(split_cpu_slots(&cpus, 3), [[0, 1], [2, 3], [4, 5]]);
+        assert_eq!(split_cpu_slots(&cpus, 16).len(), 8);
+        assert_eq!(split_cpu_slots(&[3], 4), [[3]]);
+        assert!(split_cpu_slots(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn cpu_slots_keep_layout_when_workers_change() {
+        let mut scheduler = scheduler("cpu-slots", &["--cpu-affinity"]);
+        scheduler.affinity_slots = Some(split_cpu_slots(&(0..8).collect::<Vec<_>>(), 4));
+        let mut task = Task::new("sleep 5", None).unwrap();
+        task.pin_cpus(scheduler.pick_cpu_slot());
+        assert!(task.spawn());
+        scheduler.running_pool.push(task);
+
+        // like after an OOM kill lowered the cap
+        scheduler.max_workers = 1;
+        assert_eq!(scheduler.pick_cpu_slot(), Some((1, vec![2, 3])));
+        let _ = scheduler.running_pool[0].stop();
+    }
+
+    #[test]
+    fn resume_with_commas_in_fields() {
+        let mut scheduler = scheduler("resume-commas", &[]);
+        submit(&mut scheduler, "echo 'a,b'");
+        submit(&mut scheduler, "echo c");
+        submit(&mut scheduler, "echo d");
+        // a success with commas in the reason, and a failure
+        let report = "\
+\",odd,name\",\"echo a,b\",Exited,0,1,1.000,2.000,1.000,\"done, KillIncomplete, 2 processes left after kill\"
+c,echo c,Exited,1,1,1.000,2.000,1.000,\"failed, after 1.0s\"
+";
+        std::fs::write(scheduler.report_path(), report).unwrap();
+        scheduler.resume(false);
+
+        let left: Vec<String> = scheduler.waiting_queue.iter().map(Task::get_cmd).collect();
+        assert_eq!(left, ["echo c", "echo d"]);
+
+        let entries = parse_csv_report(report);
+        assert_eq!(entries[0].name, ",odd,name");
+        assert_eq!(entries[0].cmd, "echo a,b");
+        assert_eq!(entries[1].exit_code, Some(1));
+    }
+
+    #[test]
+    fn csv_fields_round_trip() {
+        let fields = ["plain", "a,b", "say \"hi\"", "two\nlines", ""];
+        let line: Vec<String> = fields
+            .iter()
+            .map(|field| csv_field(field).into_owned())
+            .collect();
+        let contents = format!("{}\n", line.join(","));
+        let records = csv_records(&contents);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, fields);
+        assert_eq!(records[0].1, line.join(","));
+    }
+
+    #[test]
+    fn resume_old_report_with_commas_in_cmd() {
+        let entries = parse_csv_report("a,echo x,y,Exited,0,1,1.000,2.000,1.000,\n");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].cmd, "echo x,y");
+        assert_eq!(entries[0].exit_code, Some(0));
+    }
+
+    #[test]
+    fn report_in_input_order() {
+        let mut scheduler = scheduler("report-order", &["--report-order", "input"]);
+        for (index, name) in [(2, "c"), (0, "a"), (1, "b")] {
+            let mut task = Task::new(&format!("echo {}", name), Some(name)).unwrap();
+            task.mark_submitted(index);
+            task.set_status(TaskStatus::Exited);
+            scheduler.exited_pool.push(task);
+            // sorted again on every write, not only within what exited since the last one
+            scheduler.write_report();
+        }
+        let report = std::fs::read_to_string(scheduler.report_path()).unwrap();
+        let names: Vec<&str> = report.lines().map(|line| &line[..1]).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn report_removed_mid_run_is_rewritten() {
+        let mut scheduler = scheduler("report-removed", &[]);
+        for name in ["a", "b"] {
+            let mut task = Task::new(&format!("echo {}", name), Some(name)).unwrap();
+            task.set_status(TaskStatus::Exited);
+            scheduler.exited_pool.push(task);
+            scheduler.write_report();
+            if name == "a" {
+                std::fs::remove_file(scheduler.report_path()).unwrap();
+            }
+        }
+        let report = std::fs::read_to_string(scheduler.report_path()).unwrap();
+        let names: Vec<&str> = report.lines().map(|line| &line[..1]).collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn missing_cwd_counts_as_done() {
+        let mut scheduler = scheduler("missing-cwd-done", &[]);
+        submit(&mut scheduler, "cd:/nonexistent/cirno true");
+        submit(&mut scheduler, "true");
+        // the rejected task does not take a worker, the next one still starts
+        assert_eq!(scheduler.dispatch_up_to(0, 1), 1);
+        assert_eq!(scheduler.running_pool.len(), 1);
+        assert_eq!(scheduler.exited_pool.len(), 1);
+        assert!(scheduler.waiting_queue.is_empty());
+        let _ = scheduler.running_pool[0].stop();
     }
 }
@@ -1,19 +1,58 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
     fs,
-    io::Result,
-    path::Path,
+    io::{Read, Result},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
     process::{Child, Command, ExitStatus, Stdio},
     str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
     time::{Duration, Instant},
 };
 
-use crate::utils::process::kill_process_tree;
-use log::warn;
-use rustix::process::{Pid, Signal};
+use crate::utils::cli::KillScope;
+use crate::utils::clock::{unix_time, Clock, SystemClock};
+use crate::utils::process::{
+    force_kill_process_tree, get_process_tree, get_process_tree_with_depth, get_processes_with_env,
+    kill_process_tree, set_cpu_affinity, set_io_priority, Process,
+};
+use crate::utils::shell::{is_shell_operator, quote_word, split_command, split_unquoted};
+use crate::utils::unit::parse_mem_size;
+use log::{info, warn};
+use rustix::process::{
+    getrlimit, kill_process, setpriority_process, setrlimit, Pid, Resource, Rlimit, Signal,
+};
 use uuid::Uuid;
 
 const NODE_ID: [u8; 6] = [1, 1, 4, 5, 1, 4];
+// tasks with this label run until every process they started has exited,
+// even ones which detached from the tree
+const DETACH_LABEL: &str = "detach";
+// set for tasks labeled `detach`, to find their processes after reparenting
+const DETACH_ENV: &str = "CIRNO_DETACH_TOKEN";
+// rounds to re-walk the process tree when killing it
+const KILL_RETRIES: usize = 10;
+
+// as input list, read the tasks from stdin
+pub const STDIN_INPUT: &str = "-";
+// keys accepted as leading `key:value` tokens of a task line
+const TASK_OPTIONS: [&str; 13] = [
+    "max_rss",
+    "mutex",
+    "every",
+    "max_runs",
+    "priority",
+    "label",
+    "output",
+    "input",
+    "idle_timeout",
+    "retry_cmd",
+    "cd",
+    "nice",
+    "timeout",
+];
 
 #[derive(Debug, Copy, Clone)]
 pub enum TaskStatus {
@@ -22,6 +61,11 @@ pub enum TaskStatus {
     Exited,
     Timeout,
     Killed,
+    Rejected,
+    OOMKilled,
+    NotStarted,
+    UpToDate,
+    FailedFast,
 }
 
 impl Display for TaskStatus {
@@ -32,6 +76,11 @@ impl Display for TaskStatus {
             Self::Exited => "Exited",
             Self::Timeout => "Timeout",
             Self::Killed => "Killed",
+            Self::Rejected => "Rejected",
+            Self::OOMKilled => "OOMKilled",
+            Self::NotStarted => "NotStarted",
+            Self::UpToDate => "UpToDate",
+            Self::FailedFast => "FailedFast",
         };
         write!(f, "{}", display_str)
     }
@@ -43,25 +92,140 @@ pub struct Task {
     prog: String,
     args: Vec<String>,
     cmd: Command,
+    // set for every command of the task, on top of cirno's environment
+    envs: Vec<(String, String)>,
+    // working directory of every command, cirno's own if unset
+    current_dir: Option<PathBuf>,
+    // run instead of `cmd` after the first run, like with a `--resume` flag
+    retry: Option<(String, Vec<String>, Command)>,
 
     status: TaskStatus,
+    reason: Option<String>,
+
+    // output kept in memory instead of files, filled by reader threads
+    capture_output: bool,
+    captured_stdout: Arc<Mutex<Vec<u8>>>,
+    captured_stderr: Arc<Mutex<Vec<u8>>>,
+    // log files when not capturing, watched for output activity
+    stdout_file: Option<PathBuf>,
+    stderr_file: Option<PathBuf>,
+    // output goes to `logger` processes for the next run
+    journal_output: bool,
+    // value of `CIRNO_DETACH_TOKEN` for the current run
+    detach_token: Option<String>,
+    last_output_size: u64,
+    last_output_time: Option<Instant>,
+    // since when the tree has been blocked reading the terminal
+    tty_wait_since: Option<Instant>,
+    // processes of the last run found alive after it was killed
+    kill_survivors: usize,
+    // gpus given to the current run
+    gpus: Vec<u32>,
+    // cpu slot of the current run and its cpus, applied at spawn
+    cpu_slot: Option<usize>,
+    cpus: Vec<usize>,
+
     handler: Option<Child>,
+    exit_status: Option<ExitStatus>,
+    clock: Arc<dyn Clock>,
     start_time: Option<Instant>,
+    end_time: Option<Instant>,
+    busy_time: Duration,
     start_waiting_time: Option<Instant>,
+    ready_time: Option<Instant>,
+    submit_time: Option<Instant>,
+    // position among all submitted tasks
+    submit_index: usize,
+    first_start_time: Option<Instant>,
+    // wall clock of the first start and the last exit, for reports only
+    first_start_unix_time: Option<Duration>,
+    end_unix_time: Option<Duration>,
+    executions: usize,
+    // runs left after a nonzero exit
+    retries_left: usize,
+
+    // options from the task line
+    max_rss: Option<usize>,
+    nice: Option<i32>,
+    mutexes: Vec<String>,
+    every: Option<Duration>,
+    max_runs: Option<usize>,
+    priority: f64,
+    labels: Vec<String>,
+    // files made by the task and the ones it is made from
+    outputs: Vec<PathBuf>,
+    inputs: Vec<PathBuf>,
+    idle_timeout: Option<f64>,
+    // over --timeout, 0 or less never times out
+    timeout: Option<f64>,
+
+    kill_scope: KillScope,
+    // levels of the tree counted for memory and cpu, kills ignore it
+    tree_depth: Option<usize>,
 }
 
 impl Task {
-    pub fn new(cmd: &str, name_or_none: Option<&str>) -> Self {
-        let mut tokens = cmd.split_whitespace();
-        // if paninc here, it means the input is invalid
-        let prog = tokens.next().unwrap().to_string();
-        let mut args = Vec::new();
-        for token in tokens {
-            args.push(token.to_string());
+    // None for a line with only options or environment, and no command
+    pub fn new(cmd: &str, name_or_none: Option<&str>) -> Option<Self> {
+        let mut tokens = split_task_line(cmd).into_iter().peekable();
+        // leading `key:value` tokens with a known key are task options
+        let mut options = Vec::new();
+        while let Some(token) = tokens.peek() {
+            match token.split_once(':') {
+                Some((key, value)) if TASK_OPTIONS.contains(&key) => {
+                    options.push((key.to_string(), value.to_string()));
+                    tokens.next();
+                }
+                _ => break,
+            }
+        }
+        // then `KEY=VALUE` tokens set the environment, and may end with `--`
+        let mut envs = Vec::new();
+        while let Some(token) = tokens.peek() {
+            match token.split_once('=') {
+                Some((key, value)) if is_env_name(key) => {
+                    envs.push((key.to_string(), value.to_string()));
+                    tokens.next();
+                }
+                _ => break,
+            }
+        }
+        if !envs.is_empty() && tokens.peek().is_some_and(|token| token == "--") {
+            tokens.next();
+        }
+        let prog = match tokens.next() {
+            Some(prog) => prog,
+            None => {
+                warn!("Skip task `{}` without command", cmd);
+                return None;
+            }
+        };
+        let args: Vec<String> = tokens.collect();
+
+        let mut task = Task::with_args(&prog, &args, name_or_none);
+        if args.iter().any(|arg| is_shell_operator(arg)) {
+            warn!(
+                "Task {} has shell operators, they are passed to {} as arguments, use `sh -c '...'` for a shell",
+                task.name, prog
+            );
         }
+        for (key, value) in envs {
+            task.with_env(&key, &value);
+        }
+        for (key, value) in options {
+            task.apply_option(&key, &value);
+        }
+        Some(task)
+    }
+
+    // build a task from an exact argv, nothing is split or parsed as task option
+    pub fn with_args(prog: &str, args: &[String], name_or_none: Option<&str>) -> Self {
+        let prog = prog.to_string();
+        let args = args.to_vec();
         // get command obj
         let mut cmd = Command::new(&prog);
-        cmd.args(args.clone());
+        // reading stdin gets EOF instead of stealing input from the terminal
+        cmd.args(args.clone()).stdin(Stdio::null());
 
         let name = if let Some(task_name) = name_or_none {
             String::from_str(task_name).unwrap_or(String::from(Uuid::now_v1(&NODE_ID)))
@@ -74,10 +238,124 @@ impl Task {
             prog,
             args,
             cmd,
+            envs: Vec::new(),
+            current_dir: None,
+            retry: None,
             status: TaskStatus::Waiting,
+            reason: None,
+            capture_output: false,
+            captured_stdout: Arc::new(Mutex::new(Vec::new())),
+            captured_stderr: Arc::new(Mutex::new(Vec::new())),
+            stdout_file: None,
+            stderr_file: None,
+            journal_output: false,
+            detach_token: None,
+            last_output_size: 0,
+            last_output_time: None,
+            tty_wait_since: None,
+            kill_survivors: 0,
+            gpus: Vec::new(),
+            cpu_slot: None,
+            cpus: Vec::new(),
             handler: None,
+            exit_status: None,
+            clock: Arc::new(SystemClock),
             start_time: None,
+            end_time: None,
+            busy_time: Duration::from_secs(0),
             start_waiting_time: None,
+            ready_time: None,
+            submit_time: None,
+            submit_index: 0,
+            first_start_time: None,
+            first_start_unix_time: None,
+            end_unix_time: None,
+            executions: 0,
+            retries_left: 0,
+            max_rss: None,
+            nice: None,
+            mutexes: Vec::new(),
+            every: None,
+            max_runs: None,
+            priority: 0.0,
+            labels: Vec::new(),
+            outputs: Vec::new(),
+            inputs: Vec::new(),
+            idle_timeout: None,
+            timeout: None,
+
+            kill_scope: KillScope::Tree,
+            tree_depth: None,
+        }
+    }
+
+    // set a task option as if given as `key:value` in the task line,
+    // return false for an unknown key
+    pub fn set_option(&mut self, key: &str, value: &str) -> bool {
+        if !TASK_OPTIONS.contains(&key) {
+            return false;
+        }
+        self.apply_option(key, value);
+        true
+    }
+
+    fn apply_option(&mut self, key: &str, value: &str) {
+        match key {
+            "max_rss" => match parse_mem_size(value) {
+                Some(max_rss) => self.max_rss = Some(max_rss),
+                None => warn!("Ignored bad max_rss `{}` for task {}", value, self.name),
+            },
+            "mutex" => self.mutexes.push(value.to_string()),
+            "label" => self.labels.push(value.to_string()),
+            "output" => self.outputs.push(PathBuf::from(value)),
+            "input" => self.inputs.push(PathBuf::from(value)),
+            "cd" => {
+                self.current_dir(Path::new(value));
+            }
+            "nice" => match value.parse::<i32>() {
+                Ok(nice) if (-20..=19).contains(&nice) => self.nice = Some(nice),
+                _ => warn!("Ignored bad nice `{}` for task {}", value, self.name),
+            },
+            "timeout" => match value.parse::<f64>() {
+                Ok(timeout) if !timeout.is_nan() => self.timeout = Some(timeout),
+                _ => warn!("Ignored bad timeout `{}` for task {}", value, self.name),
+            },
+            "idle_timeout" => match value.parse::<f64>() {
+                Ok(idle_timeout) if idle_timeout > 0.0 => self.idle_timeout = Some(idle_timeout),
+                _ => warn!(
+                    "Ignored bad idle_timeout `{}` for task {}",
+                    value, self.name
+                ),
+            },
+            "every" => match value.parse::<f64>() {
+                Ok(every) if every > 0.0 => self.every = Some(Duration::from_secs_f64(every)),
+                _ => warn!("Ignored bad every `{}` for task {}", value, self.name),
+            },
+            "priority" => match value.parse::<f64>() {
+                Ok(priority) if priority.is_finite() => self.priority = priority,
+                _ => warn!("Ignored bad priority `{}` for task {}", value, self.name),
+            },
+            "retry_cmd" => {
+                let mut tokens = split_task_line(value).into_iter();
+                match tokens.next() {
+                    Some(prog) => {
+                        let args: Vec<String> = tokens.collect();
+                        let mut cmd = Command::new(&prog);
+                        cmd.args(&args).stdin(Stdio::null());
+                        cmd.envs(self.envs.iter().map(|(key, value)| (key, value)));
+                        if let Some(dir) = &self.current_dir {
+                            cmd.current_dir(dir);
+                        }
+                        self.retry = Some((prog, args, cmd));
+                    }
+                    None => warn!("Ignored empty retry_cmd for task {}", self.name),
+                }
+            }
+            "max_runs" => match value.parse::<usize>() {
+                Ok(max_runs) => self.max_runs = Some(max_runs),
+                Err(_) => warn!("Ignored bad max_runs `{}` for task {}", value, self.name),
+            },
+            _ => unreachable!(),
         }
     }
 
@@ -89,6 +367,14 @@ impl Task {
         self.status
     }
 
+    pub fn set_reason(&mut self, reason: &str) {
+        self.reason = Some(reason.to_string());
+    }
+
+    pub fn get_reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
     pub fn set_name(&mut self, name: &str) {
         self.name = name.to_string();
     }
@@ -97,47 +383,507 @@ impl Task {
         &self.name
     }
 
+    pub fn get_prog(&self) -> &str {
+        &self.prog
+    }
+
+    // the command of the last run, `retry_cmd` after the first one
     pub fn get_cmd(&self) -> String {
-        let cmd = &self.prog;
-        let args = self.args.join(" ");
+        let (cmd, args) = match &self.retry {
+            Some((prog, args, _)) if self.executions > 1 => (prog, args),
+            _ => (&self.prog, &self.args),
+        };
+        self.render_cmd(cmd, args)
+    }
 
-        format!("{} {}", cmd, args)
+    // if `cmd` is how `get_cmd` shows either command of the task
+    pub fn is_cmd(&self, cmd: &str) -> bool {
+        std::iter::once((&self.prog, &self.args))
+            .chain(self.retry.as_ref().map(|(prog, args, _)| (prog, args)))
+            .any(|(prog, args)| self.render_cmd(prog, args) == cmd)
     }
 
+    // like a task line, `cd:` and `KEY=VALUE` of the environment first, so tasks
+    // only differing in them are told apart, words are quoted to split back the same
+    fn render_cmd(&self, prog: &str, args: &[String]) -> String {
+        let cd = self
+            .current_dir
+            .as_ref()
+            .map(|dir| format!("cd:{} ", quote_word(&dir.to_string_lossy())))
+            .unwrap_or_default();
+        let envs = self
+            .envs
+            .iter()
+            .map(|(key, value)| format!("{}={} ", key, quote_word(value)))
+            .collect::<String>();
+        let args = args
+            .iter()
+            .map(|arg| quote_word(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!("{}{}{} {}", cd, envs, quote_word(prog), args)
+    }
+
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    pub fn get_retry_prog(&self) -> Option<&str> {
+        self.retry.as_ref().map(|(prog, _, _)| prog.as_str())
+    }
+
+    // settings kept for every run go to both commands
+    fn cmds(&mut self) -> impl Iterator<Item = &mut Command> {
+        std::iter::once(&mut self.cmd).chain(self.retry.as_mut().map(|(_, _, cmd)| cmd))
+    }
+
+    // the command the next run spawns
+    fn next_cmd(&mut self) -> &mut Command {
+        match &mut self.retry {
+            Some((_, _, cmd)) if self.executions > 0 => cmd,
+            _ => &mut self.cmd,
+        }
+    }
+
+    pub fn get_exit_status(&self) -> Option<ExitStatus> {
+        self.exit_status
+    }
+
+    // exit code of the last run, or the negated signal number if it was killed by one
+    pub fn get_exit_code(&self) -> Option<i32> {
+        let status = self.exit_status?;
+        status
+            .code()
+            .or_else(|| status.signal().map(|signal| -signal))
+    }
+
+    // timeout and every way of not finishing on its own counts as failed
+    pub fn is_failed(&self) -> bool {
+        match self.status {
+            TaskStatus::Exited => !self.exit_status.is_some_and(|status| status.success()),
+            TaskStatus::Waiting
+            | TaskStatus::Running
+            | TaskStatus::NotStarted
+            | TaskStatus::UpToDate => false,
+            _ => true,
+        }
+    }
+
+    pub fn get_timeout(&self) -> Option<f64> {
+        self.timeout
+    }
+
+    pub fn get_idle_timeout(&self) -> Option<f64> {
+        self.idle_timeout
+    }
+
+    pub fn get_nice(&self) -> Option<i32> {
+        self.nice
+    }
+
+    pub fn get_max_rss(&self) -> Option<usize> {
+        self.max_rss
+    }
+
+    pub fn get_mutexes(&self) -> &[String] {
+        &self.mutexes
+    }
+
+    pub fn get_labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.iter().any(|task_label| task_label == label)
+    }
+
+    // like make, every output exists and is newer than every input,
+    // false for a task without outputs
+    pub fn is_up_to_date(&self) -> bool {
+        if self.outputs.is_empty() {
+            return false;
+        }
+        let mtime = |path: &PathBuf| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let oldest_output = match self.outputs.iter().map(mtime).collect::<Option<Vec<_>>>() {
+            Some(mtimes) => mtimes.into_iter().min(),
+            None => return false,
+        };
+        let newest_input = match self.inputs.iter().map(mtime).collect::<Option<Vec<_>>>() {
+            Some(mtimes) => mtimes.into_iter().max(),
+            None => return false,
+        };
+        match (oldest_output, newest_input) {
+            (Some(output), Some(input)) => output > input,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn set_kill_scope(&mut self, kill_scope: KillScope) {
+        self.kill_scope = kill_scope;
+    }
+
+    pub fn set_tree_depth(&mut self, tree_depth: Option<usize>) {
+        self.tree_depth = tree_depth;
+    }
+
+    // set the nice value in the child before exec, lowering it needs privilege
+    // so it may fail and the child keeps the one of cirno
+    pub fn set_nice(&mut self, nice: i32) {
+        for cmd in self.cmds() {
+            // Safety: only a syscall runs between fork and exec
+            unsafe {
+                cmd.pre_exec(move || {
+                    let _ = setpriority_process(None, nice);
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    // cap the address space of the child before exec, allocating past it fails
+    // and most programs then abort, the hard limit is kept so it can not be raised
+    // above what cirno itself may use
+    pub fn set_mem_limit(&mut self, limit: usize) {
+        for cmd in self.cmds() {
+            // Safety: between fork and exec only async signal safe code may run, so the
+            // closure must not allocate, lock or log, it only makes two raw syscalls
+            unsafe {
+                cmd.pre_exec(move || {
+                    let maximum = getrlimit(Resource::As).maximum;
+                    let current = match maximum {
+                        Some(maximum) => maximum.min(limit as u64),
+                        None => limit as u64,
+                    };
+                    setrlimit(
+                        Resource::As,
+                        Rlimit {
+                            current: Some(current),
+                            maximum,
+                        },
+                    )?;
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    // like `set_nice`, a class needing privilege fails the same way
+    pub fn set_io_priority(&mut self, io_priority: i32) {
+        for cmd in self.cmds() {
+            // Safety: only a syscall runs between fork and exec
+            unsafe {
+                cmd.pre_exec(move || {
+                    let _ = set_io_priority(io_priority);
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    pub fn mark_submitted(&mut self, index: usize) {
+        self.submit_time = Some(self.clock.now());
+        self.submit_index = index;
+    }
+
+    pub fn get_submit_index(&self) -> usize {
+        self.submit_index
+    }
+
+    // from submission to the first start, zero if not both happened
+    pub fn queued_time(&self) -> Duration {
+        match (self.submit_time, self.first_start_time) {
+            (Some(submit_time), Some(start_time)) => {
+                start_time.saturating_duration_since(submit_time)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    // base priority raised by `aging_rate` per second since submission
+    pub fn effective_priority(&self, aging_rate: f64) -> f64 {
+        let waited = match self.submit_time {
+            Some(submit_time) => self.clock.elapsed(submit_time).as_secs_f64(),
+            None => 0.0,
+        };
+        self.priority + waited * aging_rate
+    }
+
+    pub fn is_periodic(&self) -> bool {
+        self.every.is_some()
+    }
+
+    pub fn get_executions(&self) -> usize {
+        self.executions
+    }
+
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.retries_left = max_retries;
+    }
+
+    // use up a retry after a failed run, return false if none is left
+    pub fn take_retry(&mut self) -> bool {
+        if self.retries_left == 0 {
+            return false;
+        }
+        self.retries_left -= 1;
+        self.status = TaskStatus::Waiting;
+        true
+    }
+
+    // queue a periodic task for its next run, return false if it should not run again
+    pub fn schedule_next_run(&mut self) -> bool {
+        let every = match self.every {
+            Some(every) => every,
+            None => return false,
+        };
+        if self
+            .max_runs
+            .is_some_and(|max_runs| self.executions >= max_runs)
+        {
+            return false;
+        }
+        self.status = TaskStatus::Waiting;
+        self.ready_time = Some(self.clock.now() + every);
+        true
+    }
+
+    // if the task may be started now
+    pub fn is_ready(&self) -> bool {
+        match self.ready_time {
+            Some(ready_time) => self.clock.now() >= ready_time,
+            None => true,
+        }
+    }
+
+    // resident memory of the whole process tree, `Byte` as unit
+    pub fn mem(&self) -> usize {
+        let child = match &self.handler {
+            Some(child) => child,
+            None => return 0,
+        };
+        if self.exit_status.is_some() && self.detach_token.is_some() {
+            return self
+                .detached_processes()
+                .iter()
+                .map(|process| process.mem())
+                .sum();
+        }
+        match get_process_tree_with_depth(Pid::from_child(child), true, self.tree_depth) {
+            Ok(process_list) => process_list.iter().map(|process| process.mem()).sum(),
+            Err(_) => 0,
+        }
+    }
+
+    // cpu time used by the whole process tree, exited children count once reaped
+    pub fn cpu_time(&self) -> Duration {
+        let child = match &self.handler {
+            Some(child) => child,
+            None => return Duration::ZERO,
+        };
+        match get_process_tree_with_depth(Pid::from_child(child), true, self.tree_depth) {
+            Ok(process_list) => process_list
+                .iter()
+                .map(|process| process.cpu_time(true))
+                .sum(),
+            Err(_) => Duration::ZERO,
+        }
+    }
+
+    // time of the current run, or of the last one once it has exited
     pub fn running_time(&self) -> Duration {
-        match &self.start_time {
-            Some(start_time) => start_time.elapsed(),
-            None => Duration::from_secs(0),
+        match (self.start_time, self.end_time) {
+            (Some(start_time), Some(end_time)) => end_time.saturating_duration_since(start_time),
+            (Some(start_time), None) => self.clock.elapsed(start_time),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    // time spent running over all executions
+    pub fn total_running_time(&self) -> Duration {
+        match (self.start_time, self.end_time) {
+            (Some(start_time), None) => self.busy_time + self.clock.elapsed(start_time),
+            _ => self.busy_time,
         }
     }
 
+    fn finish_run(&mut self) {
+        if let (Some(start_time), None) = (self.start_time, self.end_time) {
+            let now = self.clock.now();
+            self.busy_time += now.saturating_duration_since(start_time);
+            self.end_time = Some(now);
+            self.end_unix_time = Some(unix_time());
+        }
+    }
+
+    // since the unix epoch, `None` before the first run
+    pub fn first_start_unix_time(&self) -> Option<Duration> {
+        self.first_start_unix_time
+    }
+
+    // since the unix epoch, `None` until a run has exited
+    pub fn end_unix_time(&self) -> Option<Duration> {
+        self.end_unix_time
+    }
+
     pub fn waiting_time(&self) -> Duration {
-        match &self.start_waiting_time {
-            Some(start_time) => start_time.elapsed(),
+        match self.start_waiting_time {
+            Some(start_time) => self.clock.elapsed(start_time),
             None => Duration::from_secs(0),
         }
     }
 
     pub fn reset_waiting_time(&mut self) {
-        self.start_waiting_time = Some(Instant::now());
+        self.start_waiting_time = Some(self.clock.now());
+    }
+
+    // bytes written to stdout and stderr in the current run so far
+    fn output_size(&self) -> u64 {
+        if self.capture_output {
+            let stdout = self.captured_stdout.lock().unwrap().len();
+            let stderr = self.captured_stderr.lock().unwrap().len();
+            return (stdout + stderr) as u64;
+        }
+        [&self.stdout_file, &self.stderr_file]
+            .into_iter()
+            .flatten()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+
+    // time since the output last grew, or since start without output yet
+    pub fn idle_time(&mut self) -> Duration {
+        let size = self.output_size();
+        if size != self.last_output_size {
+            self.last_output_size = size;
+            self.last_output_time = Some(self.clock.now());
+        }
+        match self.last_output_time.or(self.start_time) {
+            Some(since) => self.clock.elapsed(since),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    // how long some process of the task has been waiting for input on `tty`,
+    // like a password prompt opening `/dev/tty`
+    pub fn tty_wait_time(&mut self, tty: (u32, u32)) -> Duration {
+        let child = match &self.handler {
+            Some(child) => child,
+            None => return Duration::ZERO,
+        };
+        let reading =
+            match get_process_tree_with_depth(Pid::from_child(child), true, self.tree_depth) {
+                Ok(process_list) => process_list
+                    .iter()
+                    .any(|process| process.is_reading_tty(tty)),
+                Err(_) => false,
+            };
+        if !reading {
+            self.tty_wait_since = None;
+            return Duration::ZERO;
+        }
+        let since = *self.tty_wait_since.get_or_insert(self.clock.now());
+        self.clock.elapsed(since)
     }
 
     fn stdout(&mut self, pipe: Stdio) -> &mut Self {
-        self.cmd.stdout(pipe);
+        self.next_cmd().stdout(pipe);
         self
     }
 
     fn stderr(&mut self, pipe: Stdio) -> &mut Self {
-        self.cmd.stderr(pipe);
+        self.next_cmd().stderr(pipe);
+        self
+    }
+
+    // kept for every run, `retry_cmd` included
+    pub fn with_env(&mut self, key: &str, value: &str) -> &mut Self {
+        for cmd in self.cmds() {
+            cmd.env(key, value);
+        }
+        self.envs.push((key.to_string(), value.to_string()));
         self
     }
 
+    // a missing directory only shows up as a failed spawn
+    pub fn current_dir(&mut self, path: &Path) -> &mut Self {
+        for cmd in self.cmds() {
+            cmd.current_dir(path);
+        }
+        self.current_dir = Some(path.to_path_buf());
+        self
+    }
+
+    // replaced on every dispatch, unlike `with_env` it is left out of `get_cmd`
+    pub fn assign_gpus(&mut self, gpus: Vec<u32>, env_key: &str) -> &mut Self {
+        let value = gpus
+            .iter()
+            .map(|gpu| gpu.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        for cmd in self.cmds() {
+            cmd.env(env_key, &value);
+        }
+        self.gpus = gpus;
+        self
+    }
+
+    pub fn get_gpus(&self) -> &[u32] {
+        &self.gpus
+    }
+
+    // the next run is pinned to `cpus`, None runs it anywhere
+    pub fn pin_cpus(&mut self, slot: Option<(usize, Vec<usize>)>) -> &mut Self {
+        (self.cpu_slot, self.cpus) = match slot {
+            Some((slot, cpus)) => (Some(slot), cpus),
+            None => (None, Vec::new()),
+        };
+        self
+    }
+
+    pub fn get_cpu_slot(&self) -> Option<usize> {
+        self.cpu_slot
+    }
+
+    // keep stdout and stderr in memory, read them by `get_stdout`/`get_stderr`
+    pub fn capture_output(&mut self, capture: bool) -> &mut Self {
+        self.capture_output = capture;
+        if capture {
+            for cmd in self.cmds() {
+                cmd.stdout(Stdio::piped());
+                cmd.stderr(Stdio::piped());
+            }
+        }
+        self
+    }
+
+    pub fn is_capturing_output(&self) -> bool {
+        self.capture_output
+    }
+
+    // output captured so far, complete once the task exited
+    pub fn get_stdout(&self) -> Vec<u8> {
+        self.captured_stdout.lock().unwrap().clone()
+    }
+
+    pub fn get_stderr(&self) -> Vec<u8> {
+        self.captured_stderr.lock().unwrap().clone()
+    }
+
     pub fn stdout_from_file(&mut self, path: &Path) -> &mut Self {
         if let Some(p) = path.parent() {
             fs::create_dir_all(p).expect("Failed to create runtime dir");
         }
         let file = fs::File::create(path).expect("Failed to create file");
         self.stdout(Stdio::from(file));
+        self.stdout_file = Some(path.to_path_buf());
+        self.journal_output = false;
         self
     }
 
@@ -147,33 +893,93 @@ impl Task {
         }
         let file = fs::File::create(path).expect("Failed to create file");
         self.stderr(Stdio::from(file));
+        self.stderr_file = Some(path.to_path_buf());
+        self.journal_output = false;
         self
     }
 
+    // pipe stdout and stderr of the next run into `logger`, one journal entry per line
+    // with the task name as identifier, stderr at the `err` priority
+    pub fn output_to_journal(&mut self) -> Result<&mut Self> {
+        let stdout = spawn_logger(&self.name, "user.info")?;
+        let stderr = spawn_logger(&self.name, "user.err")?;
+        self.stdout(stdout);
+        self.stderr(stderr);
+        self.stdout_file = None;
+        self.stderr_file = None;
+        self.journal_output = true;
+        Ok(self)
+    }
+
     pub fn spawn(&mut self) -> bool {
         if self.handler.is_some() {
             self.stop()
                 .expect("Failed to respawn, due to unknown reason.");
         }
 
-        let p = match self.cmd.spawn() {
-            Ok(p) => Some(p),
+        self.detach_token = None;
+        self.kill_survivors = 0;
+        if self.has_label(DETACH_LABEL) {
+            let token = Uuid::now_v1(&NODE_ID).to_string();
+            self.next_cmd().env(DETACH_ENV, &token);
+            self.detach_token = Some(token);
+            // set again once the direct child exits
+            self.exit_status = None;
+        }
+        let p = match self.next_cmd().spawn() {
+            Ok(mut p) => {
+                if !self.cpus.is_empty() {
+                    let pid = Pid::from_child(&p);
+                    if let Err(e) = set_cpu_affinity(pid, &self.cpus) {
+                        warn!("Failed to pin {} to cpus {:?}: {}", self.name, self.cpus, e);
+                    }
+                }
+                if self.capture_output {
+                    capture_pipe(p.stdout.take(), &self.captured_stdout);
+                    capture_pipe(p.stderr.take(), &self.captured_stderr);
+                }
+                if self.journal_output {
+                    // drop our ends of the logger pipes, so they see EOF once the task exits
+                    self.stdout(Stdio::null());
+                    self.stderr(Stdio::null());
+                    self.journal_output = false;
+                }
+                Some(p)
+            }
             Err(e) => {
-                warn!("Failed to spawn process: {}", e);
+                warn!("Failed to spawn process of {}: {}", self.name, e);
                 None
             }
         };
         if p.is_none() {
             return false;
         }
-        self.start_time = Some(Instant::now());
+        self.start_time = Some(self.clock.now());
+        self.first_start_time = self.first_start_time.or(self.start_time);
+        self.first_start_unix_time = self.first_start_unix_time.or_else(|| Some(unix_time()));
+        self.end_time = None;
+        self.last_output_size = 0;
+        self.last_output_time = None;
+        self.tty_wait_since = None;
         self.handler = p;
+        self.status = TaskStatus::Running;
+        self.executions += 1;
         true
     }
 
     pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
         if let Some(chlid) = &mut self.handler {
-            chlid.try_wait()
+            let status = chlid.try_wait()?;
+            // the direct child is done, but its detached processes still work
+            if status.is_some() && !self.detached_processes().is_empty() {
+                self.exit_status = status;
+                return Ok(None);
+            }
+            if status.is_some() {
+                self.exit_status = status;
+                self.finish_run();
+            }
+            Ok(status)
         } else {
             Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
@@ -182,35 +988,154 @@ impl Task {
         }
     }
 
+    // processes left by a `detach` task, empty for other tasks
+    fn detached_processes(&self) -> Vec<Process> {
+        let token = match &self.detach_token {
+            Some(token) => token,
+            None => return Vec::new(),
+        };
+        match get_processes_with_env(DETACH_ENV, token) {
+            Ok(process_list) => process_list
+                .into_iter()
+                .filter(|process| process.is_alive())
+                .collect(),
+            Err(e) => {
+                warn!("Failed to find detached processes of {}: {}", self.name, e);
+                Vec::new()
+            }
+        }
+    }
+
     pub fn stop(&mut self) -> Result<Option<ExitStatus>> {
+        // once reparented, detached processes are outside the tree of the child
+        let mut killed = self.detached_processes();
+        for process in &killed {
+            let _ = kill_process(process.get_pid(), Signal::Kill);
+        }
         let p = self.handler.take();
-        match p {
+        let result = match p {
             Some(mut child) => {
                 let status = child.try_wait()?;
-                match status {
-                    Some(status) => Ok(Some(status)),
+                let status = match status {
+                    Some(status) => status,
                     None => {
                         // use kill signl to stop process forcely.
-                        match kill_process_tree(Pid::from_child(&child), Signal::Kill, true) {
-                            Ok(_) => Ok(Some(child.wait()?)),
-                            Err(_) => unreachable!(),
+                        let pid = Pid::from_child(&child);
+                        // walk the tree before killing, survivors get reparented away
+                        if let Ok(process_list) = get_process_tree(pid, false) {
+                            killed.extend(process_list);
                         }
+                        match self.kill_scope {
+                            KillScope::Direct => {
+                                // its children are left alone, so they are no survivors
+                                killed.retain(|process| process.get_pid() == pid);
+                                let _ = kill_process(pid, Signal::Kill);
+                            }
+                            KillScope::Tree => {
+                                if let Err(e) = force_kill_process_tree(pid, true, KILL_RETRIES) {
+                                    warn!("Failed to walk process tree of {}: {}", self.name, e)
+                                }
+                            }
+                        }
+                        child.wait()?
                     }
-                }
+                };
+                self.exit_status = Some(status);
+                self.finish_run();
+                Some(status)
             }
-            None => Ok(None),
+            None => None,
+        };
+        self.verify_killed(&killed);
+        Ok(result)
+    }
+
+    // check that nothing of a killed task is left running
+    fn verify_killed(&mut self, killed: &[Process]) {
+        let survivors: Vec<String> = killed
+            .iter()
+            .filter(|process| process.is_alive())
+            .map(|process| process.get_pid().as_raw_nonzero().to_string())
+            .collect();
+        if survivors.is_empty() {
+            return;
         }
+        warn!(
+            "{} processes of {} survived kill: {}",
+            survivors.len(),
+            self.name,
+            survivors.join(", ")
+        );
+        self.kill_survivors += survivors.len();
     }
 
+    pub fn get_kill_survivors(&self) -> usize {
+        self.kill_survivors
+    }
+
+    // with `KillScope::Direct` only the direct child is signaled, whatever `with_self` is
     pub fn signal(&self, signal: Signal, with_self: bool) -> Result<bool> {
-        if let Some(child) = &self.handler {
-            kill_process_tree(Pid::from_child(child), signal, with_self)
-        } else {
-            Ok(false)
+        let child = match &self.handler {
+            Some(child) => child,
+            None => return Ok(false),
+        };
+        if self.exit_status.is_none() || self.detach_token.is_none() {
+            return match self.kill_scope {
+                KillScope::Direct => {
+                    kill_process(Pid::from_child(child), signal)?;
+                    Ok(true)
+                }
+                KillScope::Tree => kill_process_tree(Pid::from_child(child), signal, with_self),
+            };
+        }
+        // only detached processes are left
+        for process in self.detached_processes() {
+            let _ = kill_process(process.get_pid(), signal);
         }
+        Ok(true)
     }
 }
 
+// like a shell variable, `[A-Za-z_][A-Za-z0-9_]*`
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// words of a task line with shell quoting, a line with an open quote falls back
+// to splitting by whitespace
+fn split_task_line(line: &str) -> Vec<String> {
+    match split_command(line) {
+        Some(words) => words,
+        None => {
+            warn!("Unbalanced quotes in `{}`, split by whitespace", line);
+            line.split_whitespace().map(str::to_string).collect()
+        }
+    }
+}
+
+// read a child pipe until eof in background, the previous run's output is dropped
+fn capture_pipe<R: Read + Send + 'static>(pipe: Option<R>, buffer: &Arc<Mutex<Vec<u8>>>) {
+    buffer.lock().unwrap().clear();
+    let mut pipe = match pipe {
+        Some(pipe) => pipe,
+        None => return,
+    };
+    let buffer = Arc::clone(buffer);
+    thread::spawn(move || {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match pipe.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => buffer.lock().unwrap().extend_from_slice(&chunk[..n]),
+            }
+        }
+    });
+}
+
 impl Display for Task {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let args_str = self.args.join(" ");
@@ -227,8 +1152,56 @@ impl Drop for Task {
     }
 }
 
-pub fn gen_tasks_from_file(filename: &Path, with_task_name: bool) -> Vec<Task> {
-    let contents = fs::read_to_string(filename).expect("Failed to read task list");
+// where the name of a task comes from in the task list
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaskNaming {
+    // a generated uuid
+    Generated,
+    // `name,command`
+    Comma,
+    // the first whitespace separated token, the rest is the command
+    FirstToken,
+}
+
+impl TaskNaming {
+    pub fn is_named(&self) -> bool {
+        *self != TaskNaming::Generated
+    }
+}
+
+// a `logger` reading lines from the returned pipe, reaped aside once it is done
+fn spawn_logger(tag: &str, priority: &str) -> Result<Stdio> {
+    let mut logger = Command::new("logger")
+        .arg("-t")
+        .arg(tag)
+        .arg("-p")
+        .arg(priority)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let pipe = logger.stdin.take().map(Stdio::from);
+    thread::spawn(move || logger.wait());
+    pipe.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "no stdin of logger"))
+}
+
+pub fn gen_tasks_from_file(filename: &Path, naming: TaskNaming) -> Result<Vec<Task>> {
+    let contents = String::from_utf8(read_input(filename)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(gen_tasks_from_str(&contents, naming))
+}
+
+// the whole task list, `-` reads stdin until it is closed
+fn read_input(filename: &Path) -> Result<Vec<u8>> {
+    if filename == Path::new(STDIN_INPUT) {
+        let mut contents = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut contents)?;
+        Ok(contents)
+    } else {
+        fs::read(filename)
+    }
+}
+
+fn gen_tasks_from_str(contents: &str, naming: TaskNaming) -> Vec<Task> {
     let contents = contents.trim();
     if contents.is_empty() {
         return Vec::new();
@@ -245,21 +1218,407 @@ pub fn gen_tasks_from_file(filename: &Path, with_task_name: bool) -> Vec<Task> {
             continue;
         }
 
-        let mut parts = line.split(',');
-        let first = parts.next().expect("Bad format for input task list");
+        let task = match naming {
+            // a quoted `,` belongs to the command
+            TaskNaming::Generated => match split_unquoted(line, ',').first() {
+                Some(first) => Task::new(first, None),
+                None => {
+                    warn!("Skip task {} in bad format", line);
+                    continue;
+                }
+            },
+            // skipped rather than failing, the list may be watched by a running batch
+            TaskNaming::Comma => match split_unquoted(line, ',')[..] {
+                [first, second, ..] => Task::new(second, Some(first)),
+                _ => {
+                    warn!("Skip task {} without name, maybe task_name missing", line);
+                    continue;
+                }
+            },
+            TaskNaming::FirstToken => match line.split_once(char::is_whitespace) {
+                Some((name, cmd)) if !cmd.trim().is_empty() => Task::new(cmd, Some(name)),
+                _ => {
+                    warn!("Skip task {} without command", line);
+                    continue;
+                }
+            },
+        };
+
+        if let Some(task) = task {
+            task_list.push(task);
+        }
+    }
+
+    task_list
+}
+
+// follow a task list being appended to, only complete lines are taken
+#[derive(Debug)]
+pub struct InputWatcher {
+    filename: PathBuf,
+    naming: TaskNaming,
+    only: Vec<String>,
+    exclude: Vec<String>,
+    // bytes consumed so far, always just after a newline
+    offset: usize,
+}
+
+impl InputWatcher {
+    pub fn new(filename: &Path, naming: TaskNaming, only: &[String], exclude: &[String]) -> Self {
+        InputWatcher {
+            filename: filename.to_path_buf(),
+            naming,
+            only: only.to_vec(),
+            exclude: exclude.to_vec(),
+            offset: 0,
+        }
+    }
+
+    // tasks from the lines completed since the last poll
+    pub fn poll(&mut self) -> Result<Vec<Task>> {
+        let contents = fs::read(&self.filename)?;
+        if contents.len() < self.offset {
+            // lines seen before can not be told apart, only follow new ones
+            warn!("Task list {} was truncated", self.filename.display());
+            self.offset = contents
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(0, |pos| pos + 1);
+            return Ok(Vec::new());
+        }
+        let end = match contents[self.offset..]
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+        {
+            Some(pos) => self.offset + pos + 1,
+            None => return Ok(Vec::new()),
+        };
+        let lines = String::from_utf8_lossy(&contents[self.offset..end]).into_owned();
+        self.offset = end;
+
+        let mut tasks = gen_tasks_from_str(&lines, self.naming);
+        filter_tasks(&mut tasks, &self.only, &self.exclude);
+        Ok(tasks)
+    }
+}
+
+// read NUL separated argv records, each terminated by a double NUL,
+// as `find -print0` style tools produce
+pub fn gen_tasks_from_null_file(filename: &Path, naming: TaskNaming) -> Result<Vec<Task>> {
+    let contents = read_input(filename)?;
+    let contents = String::from_utf8(contents)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let mut task_list = Vec::new();
+    for record in contents.split("\0\0") {
+        // the last one is empty, or a newline left by an editor
+        let record = record.trim_start_matches('\0');
+        if record.trim().is_empty() {
+            continue;
+        }
+        let mut fields = record.split('\0');
+        let name = if naming.is_named() {
+            fields.next()
+        } else {
+            None
+        };
+        let prog = fields.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Bad format for input task list, maybe command missing",
+            )
+        })?;
+        let args: Vec<String> = fields.map(|field| field.to_string()).collect();
+
+        task_list.push(Task::with_args(prog, &args, name));
+    }
 
-        let task = if with_task_name {
-            let second = parts
-                .next()
-                .expect("Bad format for input task list, maybe task_name missing");
+    Ok(task_list)
+}
 
-            Task::new(second, Some(first))
+// keep tasks with any label of `only` (all if empty) and none of `exclude`
+pub fn filter_tasks(tasks: &mut Vec<Task>, only: &[String], exclude: &[String]) {
+    let before = tasks.len();
+    tasks.retain(|task| {
+        (only.is_empty() || only.iter().any(|label| task.has_label(label)))
+            && !exclude.iter().any(|label| task.has_label(label))
+    });
+    if tasks.len() < before {
+        info!("Filtered out {} tasks by label", before - tasks.len());
+    }
+}
+
+// drop tasks running the same command as an earlier one, with `by_name`
+// tasks sharing a name must also share the command, or it is an error
+pub fn dedup_tasks(tasks: &mut Vec<Task>, by_name: bool) -> Result<()> {
+    let before = tasks.len();
+    let mut seen: HashMap<String, String> = HashMap::new();
+    let mut deduped = Vec::with_capacity(tasks.len());
+    for task in tasks.drain(..) {
+        let cmd = task.get_cmd();
+        let key = if by_name {
+            task.get_name().to_string()
         } else {
-            Task::new(first, None)
+            cmd.clone()
         };
+        match seen.get(&key) {
+            Some(seen_cmd) if *seen_cmd == cmd => continue,
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("task name `{}` is used by different commands", key),
+                ))
+            }
+            None => {
+                seen.insert(key, cmd);
+                deduped.push(task);
+            }
+        }
+    }
+    *tasks = deduped;
+    if tasks.len() < before {
+        info!("Removed {} duplicate tasks", before - tasks.len());
+    }
+    Ok(())
+}
 
-        task_list.push(task);
+// fisher-yates with splitmix64, the same seed always gives the same order
+pub fn shuffle_tasks(tasks: &mut [Task], seed: u64) {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    for i in (1..tasks.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        tasks.swap(i, j);
     }
+}
 
-    task_list
+// read task options keyed by task name from a toml or json (by extension) file
+// and set them on the matching tasks
+// an error if the file can not be read or is not a table of tables,
+// unknown options and names only give a warning
+pub fn apply_task_meta(tasks: &mut [Task], filename: &Path) -> Result<()> {
+    let contents = fs::read_to_string(filename)?;
+    let is_json = filename.extension().is_some_and(|ext| ext == "json");
+    let meta = if is_json {
+        parse_json_meta(&contents)?
+    } else {
+        parse_toml_meta(&contents)?
+    };
+
+    let mut tasks_by_name: HashMap<String, Vec<&mut Task>> = HashMap::new();
+    for task in tasks.iter_mut() {
+        tasks_by_name
+            .entry(task.get_name().to_string())
+            .or_default()
+            .push(task);
+    }
+
+    for (name, options) in meta {
+        let matched = match tasks_by_name.get_mut(&name) {
+            Some(matched) => matched,
+            None => {
+                warn!("Task meta for `{}` matches no task in the list", name);
+                continue;
+            }
+        };
+        for task in matched.iter_mut() {
+            for (key, value) in &options {
+                if !task.set_option(key, value) {
+                    warn!("Ignored unknown task meta `{}` for task {}", key, name);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// (task name, [(key, value)]), arrays are expanded into repeated keys
+type TaskMeta = Vec<(String, Vec<(String, String)>)>;
+
+fn bad_meta(message: impl Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("bad format for task meta, {}", message),
+    )
+}
+
+fn parse_toml_meta(contents: &str) -> Result<TaskMeta> {
+    let table: toml::Table = contents.parse().map_err(bad_meta)?;
+    let value = serde_json::to_value(table).map_err(bad_meta)?;
+    parse_meta_value(&value)
+}
+
+fn parse_json_meta(contents: &str) -> Result<TaskMeta> {
+    let value: serde_json::Value = serde_json::from_str(contents).map_err(bad_meta)?;
+    parse_meta_value(&value)
+}
+
+fn parse_meta_value(value: &serde_json::Value) -> Result<TaskMeta> {
+    let table = value
+        .as_object()
+        .ok_or_else(|| bad_meta("should be a table"))?;
+    let mut meta = Vec::new();
+    for (name, options) in table {
+        let options = options
+            .as_object()
+            .ok_or_else(|| bad_meta(format!("`{}` should be a table", name)))?;
+        let mut pairs = Vec::new();
+        for (key, value) in options {
+            let values = match value {
+                serde_json::Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                let value = match value {
+                    serde_json::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                pairs.push((key.clone(), value));
+            }
+        }
+        meta.push((name.clone(), pairs));
+    }
+    Ok(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(line: &str) -> Task {
+        Task::new(line, None).unwrap()
+    }
+
+    #[test]
+    fn env_is_part_of_cmd() {
+        let task = task("A=1 B=2 -- echo hi");
+        assert_eq!(task.get_prog(), "echo");
+        assert_eq!(task.get_cmd(), "A=1 B=2 echo hi");
+        assert!(task.is_cmd("A=1 B=2 echo hi"));
+        assert!(!task.is_cmd("echo hi"));
+    }
+
+    #[test]
+    fn env_cmd_round_trip() {
+        for line in ["A=1 echo hi", "A=1 B= -- echo hi", "timeout:5 A=1 echo"] {
+            let cmd = task(line).get_cmd();
+            assert_eq!(task(&cmd).get_cmd(), cmd, "from `{}`", line);
+        }
+    }
+
+    #[test]
+    fn dedup_keeps_tasks_with_other_env() {
+        let mut tasks = gen_tasks_from_str(
+            "A=1 echo hi\nA=2 echo hi\nA=1 echo hi\necho hi",
+            TaskNaming::Generated,
+        );
+        dedup_tasks(&mut tasks, false).unwrap();
+        let cmds: Vec<String> = tasks.iter().map(|task| task.get_cmd()).collect();
+        assert_eq!(cmds, ["A=1 echo hi", "A=2 echo hi", "echo hi"]);
+    }
+
+    #[test]
+    fn cwd_is_part_of_cmd() {
+        let task = task("cd:/tmp A=1 -- pwd");
+        assert_eq!(task.get_cmd(), "cd:/tmp A=1 pwd ");
+        assert_eq!(self::task(&task.get_cmd()).get_cmd(), task.get_cmd());
+
+        let mut tasks = gen_tasks_from_str("cd:/tmp pwd\ncd:/ pwd\npwd", TaskNaming::Generated);
+        dedup_tasks(&mut tasks, false).unwrap();
+        assert_eq!(tasks.len(), 3);
+    }
+
+    #[test]
+    fn missing_cwd_fails_to_spawn() {
+        let mut task = task("cd:/nonexistent/cirno pwd");
+        assert!(!task.spawn());
+        assert_eq!(task.get_executions(), 0);
+        assert!(task.get_exit_status().is_none());
+    }
+
+    #[test]
+    fn cmd_is_quoted() {
+        let task = task(r#"A='x y' printf "%s\n" 'a b' "it's" '' plain"#);
+        let cmd = task.get_cmd();
+        assert_eq!(cmd, r#"A='x y' printf '%s\n' 'a b' 'it'\''s' '' plain"#);
+        let again = self::task(&cmd);
+        assert_eq!(again.get_prog(), "printf");
+        assert_eq!(again.args, ["%s\\n", "a b", "it's", "", "plain"]);
+        assert_eq!(again.get_cmd(), cmd);
+    }
+
+    #[test]
+    fn quoted_comma_stays_in_cmd() {
+        let tasks = gen_tasks_from_str("echo 'a,b' \"c,d\",ignored", TaskNaming::Generated);
+        assert_eq!(tasks[0].args, ["a,b", "c,d"]);
+
+        let tasks = gen_tasks_from_str("job,echo 'a,b'", TaskNaming::Comma);
+        assert_eq!(tasks[0].get_name(), "job");
+        assert_eq!(tasks[0].args, ["a,b"]);
+    }
+
+    #[test]
+    fn skip_line_without_command() {
+        for line in ["timeout:5", "A=B", "A=B --", "label:x A=B --"] {
+            assert!(Task::new(line, None).is_none(), "from `{}`", line);
+        }
+        let tasks = gen_tasks_from_str("timeout:5\nA=B --\necho hi", TaskNaming::Generated);
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].get_cmd(), "echo hi");
+    }
+
+    #[test]
+    fn bad_task_meta_is_an_error() {
+        let dir = std::env::temp_dir();
+        let mut tasks = gen_tasks_from_str("a echo a", TaskNaming::FirstToken);
+        for (file, contents) in [
+            ("bad.toml", "a = ["),
+            ("flat.toml", "a = 1"),
+            ("list.json", "[1, 2]"),
+            ("flat.json", "{\"a\": 1}"),
+        ] {
+            let path = dir.join(format!("cirno-meta-{}-{}", std::process::id(), file));
+            fs::write(&path, contents).unwrap();
+            let res = apply_task_meta(&mut tasks, &path);
+            let _ = fs::remove_file(&path);
+            let e = res.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData, "from {}", file);
+        }
+        let missing = dir.join("cirno-meta-missing.toml");
+        assert!(apply_task_meta(&mut tasks, &missing).is_err());
+    }
+
+    #[test]
+    fn task_meta_sets_options() {
+        let path = std::env::temp_dir().join(format!("cirno-meta-{}.toml", std::process::id()));
+        fs::write(&path, "[a]\ntimeout = 5\nlabel = [\"x\", \"y\"]\n").unwrap();
+        let mut tasks = gen_tasks_from_str("a echo a\nb echo b", TaskNaming::FirstToken);
+        let res = apply_task_meta(&mut tasks, &path);
+        let _ = fs::remove_file(&path);
+        res.unwrap();
+        assert_eq!(tasks[0].get_timeout(), Some(5.0));
+        assert_eq!(tasks[0].get_labels(), ["x", "y"]);
+        assert!(tasks[1].get_labels().is_empty());
+    }
+
+    #[test]
+    fn watcher_skips_line_without_name() {
+        let path = std::env::temp_dir().join(format!("cirno-watch-{}.txt", std::process::id()));
+        fs::write(&path, "a,echo a\n").unwrap();
+        let mut watcher = InputWatcher::new(&path, TaskNaming::Comma, &[], &[]);
+        assert_eq!(watcher.poll().unwrap().len(), 1);
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, b"echo no name\nb,echo b\n").unwrap();
+        let tasks = watcher.poll().unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].get_name(), "b");
+    }
 }
@@ -1,3 +1,6 @@
 pub mod cli;
+pub mod clock;
 pub mod gpu;
 pub mod process;
+pub mod shell;
+pub mod unit;
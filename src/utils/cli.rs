@@ -1,17 +1,176 @@
-use clap::Parser;
-use clap::{arg, command};
+use crate::utils::gpu::GpuVendor;
+use crate::utils::process::{io_priority_name, parse_io_priority, parse_signal, signal_name};
+use crate::utils::unit::parse_mem_size;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use rustix::process::Signal;
+use std::ffi::OsString;
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitCodeMode {
+    /// always exit with 0
+    Zero,
+    /// 1 if any task failed, 2 if any task timed out, 3 for both
+    Bits,
+}
+
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("CIRNO_GIT_HASH"), ")");
+
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ngit: ",
+    env!("CIRNO_GIT_HASH"),
+    "\nbuilt: ",
+    env!("CIRNO_BUILD_TIME"),
+    "\ngpu: ",
+    env!("CIRNO_GPU_VENDORS")
+);
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    /// one task per line, split by whitespace
+    Line,
+    /// NUL separated argv, each task terminated by a double NUL
+    Null,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportOrder {
+    /// tasks in the order they finished
+    Completion,
+    /// tasks in the order they were submitted, stable between runs
+    Input,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// `cirno_task_pair.log`, a line per task
+    Csv,
+    /// `cirno_task_pair.json`, an array of objects with the csv columns as keys
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    /// `<name>.log` and `<name>.err` in run_dir
+    File,
+    /// lines go to the system journal through `logger`, tagged with the task name
+    Journal,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KillScope {
+    /// signal only the direct child, it manages its own children
+    Direct,
+    /// signal the whole process tree of a task
+    Tree,
+}
+
+// `cirno bench ...`, parsed before `Args` since it has no task list
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    name = "cirno bench",
+    bin_name = "cirno bench",
+    version = VERSION,
+    about = "Run generated sleep tasks through the scheduler to measure its overhead"
+)]
+pub struct BenchArgs {
+    #[arg(long, default_value_t = 100, help = "how many tasks to generate")]
+    pub tasks: usize,
+
+    #[arg(
+        long,
+        default_value_t = 0.1,
+        help = "how long each task sleeps, seconds"
+    )]
+    pub duration: f64,
+
+    #[arg(
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        help = "options for the batch as for a normal run, `-w` is required, like `-w 8 --tick-rate 10`"
+    )]
+    pub cirno_args: Vec<String>,
+}
+
+// `cirno ctl ...`, a client for the socket of `--socket-file`
+#[derive(Parser, Debug)]
+#[command(
+    name = "cirno ctl",
+    bin_name = "cirno ctl",
+    version = VERSION,
+    about = "Send commands to a running cirno through its control socket"
+)]
+pub struct CtlArgs {
+    #[arg(help = "the --socket-file of the running cirno")]
+    pub socket_file: String,
+
+    #[arg(
+        required = true,
+        help = "commands, like `status` or `workers=8`, each answered on a line"
+    )]
+    pub commands: Vec<String>,
+}
+
+const EXAMPLES: &str = "Examples:
+  cirno -w 4 tasks.list
+  cirno -w 8 --min-workers 2 -p 2G --timeout 3600 tasks.list
+  cirno -w 4 --with-task-name --only train tasks.list
+  cirno bench --tasks 1000 --duration 0.01 -w 16 --tick-rate 20
+  cirno ctl cirno.sock status
+
+`bench` and `ctl` as the first argument run the subcommands, pass `./bench` for a task list named so";
+
+// settings come from explicit flags first, then the `--config` file, then the defaults,
+// see `Args::parse_with_config`
+#[derive(Parser, Debug)]
+#[command(
+    author,
+    version = VERSION,
+    long_version = LONG_VERSION,
+    about,
+    long_about = None,
+    after_help = EXAMPLES
+)]
 pub struct Args {
-    pub input_list: String,
+    #[arg(
+        required = true,
+        num_args = 1..,
+        help = "files with a task per line, run one after another, `-` to read one from stdin"
+    )]
+    pub input_list: Vec<String>,
 
-    #[arg(short, long)]
+    #[arg(short, long, visible_alias = "max-workers")]
     pub workers: usize,
 
+    #[arg(
+        long,
+        help = "scale the worker cap between this and --workers with system headroom"
+    )]
+    pub min_workers: Option<usize>,
+
     #[arg(short, long, default_value_t = 2)]
     pub force_workers: usize,
 
+    #[arg(
+        long,
+        help = "at most this many tasks labeled `heavy` run at the same time, tasks labeled `exclusive` always run alone"
+    )]
+    pub heavy_workers: Option<usize>,
+
+    #[arg(
+        long,
+        help = "start the worker cap at --force-workers, raise it by one each healthy tick"
+    )]
+    pub ramp_up: bool,
+
+    #[arg(
+        long,
+        default_value_t = 4,
+        help = "spawn at most this many tasks in one tick, keeps the loop responsive when fork is slow"
+    )]
+    pub max_spawns_per_tick: usize,
+
     #[arg(short, long, default_value_t = -1.0, help = "set smaller than 0 to disable timeout, seconds")]
     pub timeout: f64,
 
@@ -22,6 +181,50 @@ pub struct Args {
     )]
     pub timeout_wait: f64,
 
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_timeout_step,
+        value_name = "SIGNAL[:SECONDS],...",
+        help = "signals for timed out tasks in order, each followed by SECONDS (default --timeout-wait) before the next and a kill after the last, like `TERM:10,INT:5,KILL`; unset sends ALRM until --timeout-wait, then INT and KILL"
+    )]
+    pub timeout_signal: Vec<(Signal, Option<f64>)>,
+
+    #[arg(
+        long,
+        default_value_t = -1.0,
+        help = "time out tasks without new output for this long, smaller than 0 to disable, seconds"
+    )]
+    pub idle_timeout: f64,
+
+    #[arg(
+        long,
+        default_value_t = 300.0,
+        help = "on SIGTERM stop starting tasks and wait this long for running ones before killing them, 0 to wait until they finish, seconds"
+    )]
+    pub drain_timeout: f64,
+
+    #[arg(
+        long,
+        default_value_t = 2,
+        help = "ticks to wait for a timed out task to exit after SIGINT/SIGALRM before SIGKILL"
+    )]
+    pub force_stop_retries: usize,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "run a task exiting nonzero again, up to this many times"
+    )]
+    pub max_retries: usize,
+
+    #[arg(
+        long,
+        action,
+        help = "skip tasks a previous report in run_dir has as Exited with code 0, matched by name with task names, by command otherwise"
+    )]
+    pub resume: bool,
+
     #[arg(long, default_value_t = 1.0)]
     pub tick_rate: f64,
 
@@ -31,21 +234,148 @@ pub struct Args {
     #[arg(long, default_value_t = 0.7, help = "use ratio of total mem")]
     pub low_mem_thres: f64,
 
-    #[arg(short, long, default_value_t = 4294967296, help = "Byte as unit")]
+    #[arg(
+        short,
+        long,
+        default_value_t = 4294967296,
+        value_parser = parse_per_task_mem,
+        help = "like `4G` or `512M` (binary units), `auto` or 0 for total mem / workers"
+    )]
     pub per_task_mem: usize,
 
-    #[arg(short, long, default_value_t = 0, help = "Byte as unit")]
+    #[arg(
+        short,
+        long,
+        default_value_t = 0,
+        value_parser = parse_mem_arg,
+        help = "like `4G` or `512M` (binary units), plain number as Byte"
+    )]
     pub reversed_mem: usize,
 
+    #[arg(
+        long,
+        value_parser = parse_mem_arg,
+        help = "cap the address space of every task with RLIMIT_AS, like `8G`, allocations past it fail; virtual memory counts, so programs reserving a lot of it may need a higher value"
+    )]
+    pub task_mem_limit: Option<usize>,
+
+    #[arg(
+        long,
+        default_value = "KILL",
+        value_parser = parse_signal_arg,
+        help = "signal to stop a task when memory is insufficient, KILL after --mem-kill-grace"
+    )]
+    pub mem_kill_signal: Signal,
+
+    #[arg(
+        long,
+        default_value_t = 10.0,
+        help = "wait for task to quit after --mem-kill-signal, seconds"
+    )]
+    pub mem_kill_grace: f64,
+
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "never stop a task for memory before it has run this long, seconds"
+    )]
+    pub min_run_before_kill: f64,
+
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "report tasks failing sooner than this as FailedFast, seconds"
+    )]
+    pub min_healthy_runtime: f64,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = KillScope::Tree,
+        help = "which processes of a task get signaled on stop"
+    )]
+    pub kill_scope: KillScope,
+
+    #[arg(
+        long,
+        help = "levels of a task's process tree counted for memory and cpu, deeper processes are missed, kills always walk the whole tree [default: unlimited]"
+    )]
+    pub tree_depth: Option<usize>,
+
     #[arg(short, long, default_value_t = 0.8)]
     pub load_avg_thres: f64,
 
+    #[arg(
+        long,
+        default_value_t = -1.0,
+        help = "use ratio of total swap, set smaller than 0 to disable"
+    )]
+    pub swap_thres: f64,
+
+    #[arg(
+        long,
+        help = "only start this many tasks, the rest are reported as NotStarted"
+    )]
+    pub limit: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        help = "priority gained by a waiting task per second in queue"
+    )]
+    pub priority_aging: f64,
+
     #[arg(short = 'd', long, default_value = "run")]
     pub run_dir: String,
 
+    #[arg(
+        long,
+        help = "append cirno's own log to this file instead of stderr, progress bars stay on the terminal"
+    )]
+    pub log_file: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "spread task logs into this many subdirectories of run_dir, 0 to disable"
+    )]
+    pub log_shard: usize,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputMode::File,
+        help = "where task stdout and stderr go"
+    )]
+    pub output_mode: OutputMode,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ReportOrder::Completion,
+        help = "order of tasks in the task report"
+    )]
+    pub report_order: ReportOrder,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ReportFormat::Csv,
+        help = "format of the task report in run_dir"
+    )]
+    pub report_format: ReportFormat,
+
     #[arg(long, action, help = "if cirno will consider gpu mem")]
     pub with_gpu: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = GpuVendor::Nvidia,
+        help = "which tool --with-gpu asks for gpu memory"
+    )]
+    pub gpu_vendor: GpuVendor,
+
     #[arg(
         long,
         action,
@@ -53,10 +383,685 @@ pub struct Args {
     )]
     pub with_task_name: bool,
 
+    #[arg(
+        long,
+        action,
+        help = "take the first whitespace separated token of each line as task name, the rest is the command"
+    )]
+    pub name_first_token: bool,
+
     #[arg(
         long,
         default_value_t = 0.72,
         help = "thershold for free mem in a card to be consdier as free card"
     )]
     pub gpu_mem_thres: f64,
+
+    #[arg(
+        long,
+        default_value_t = -1.0,
+        help = "a card busier than this ratio of time is not free even with free mem, set smaller than 0 to disable"
+    )]
+    pub gpu_util_thres: f64,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "with --with-gpu, give each task this many free cards of its own through CUDA_VISIBLE_DEVICES, 0 to only check for a free card"
+    )]
+    pub gpus_per_task: usize,
+
+    #[arg(
+        long,
+        action,
+        help = "pin each running task to its own range of cpus, the cpus split evenly between --workers slots"
+    )]
+    pub cpu_affinity: bool,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "hold new tasks while free space or inodes in these dirs are low, comma separated"
+    )]
+    pub watch_dir: Vec<String>,
+
+    #[arg(
+        long,
+        default_value_t = 0.05,
+        help = "ratio of free space and of free inodes a --watch-dir must keep"
+    )]
+    pub watch_dir_thres: f64,
+
+    #[arg(
+        long,
+        default_value_t = -1.0,
+        help = "hold new tasks while the busiest disk is busy for more than this ratio of time, set smaller than 0 to disable"
+    )]
+    pub disk_util_thres: f64,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "only allow tasks running these programs, comma separated"
+    )]
+    pub allow_commands: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "reject tasks running these programs, comma separated"
+    )]
+    pub deny_commands: Vec<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = InputFormat::Line,
+        help = "format of the input task list"
+    )]
+    pub input_format: InputFormat,
+
+    #[arg(
+        long,
+        action,
+        help = "keep reading tasks appended to the list, run until stopped"
+    )]
+    pub watch_input: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "keep running when all tasks are done, exit only on SIGINT or SIGTERM"
+    )]
+    pub daemon: bool,
+
+    #[arg(long, action, help = "treat an empty task list as an error")]
+    pub fail_on_empty: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "drop tasks with the same command, or the same name with --with-task-name"
+    )]
+    pub dedup: bool,
+
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        value_name = "SEED",
+        help = "run tasks in random order, --shuffle=SEED makes the order reproducible"
+    )]
+    pub shuffle: Option<Option<u64>>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "only run tasks with any of these labels, comma separated"
+    )]
+    pub only: Vec<String>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "skip tasks with any of these labels, comma separated"
+    )]
+    pub exclude: Vec<String>,
+
+    #[arg(long, help = "toml or json file with task options keyed by task name")]
+    pub task_meta: Option<String>,
+
+    #[arg(
+        long,
+        help = "shell command to run when system status changes, gets CIRNO_STATUS and CIRNO_PREV_STATUS"
+    )]
+    pub on_status_change: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ExitCodeMode::Zero,
+        help = "how the exit code of cirno reflects task results"
+    )]
+    pub exit_code_mode: ExitCodeMode,
+
+    #[arg(
+        long,
+        allow_hyphen_values = true,
+        help = "nice value for cirno itself, negative keeps the loop responsive under load (needs privilege)"
+    )]
+    pub self_nice: Option<i32>,
+
+    #[arg(
+        long,
+        allow_hyphen_values = true,
+        help = "nice value for every task, a `nice:N` task option overrides it"
+    )]
+    pub nice: Option<i32>,
+
+    #[arg(
+        long,
+        value_parser = parse_io_priority_arg,
+        value_name = "CLASS:LEVEL",
+        help = "io priority for every task like `ionice`, `best-effort:7`, `idle` or `realtime:0` (needs privilege)"
+    )]
+    pub ionice: Option<i32>,
+
+    #[arg(
+        long,
+        help = "touch this file every tick, a stale mtime means the loop has hung"
+    )]
+    pub heartbeat_file: Option<String>,
+
+    #[arg(
+        long,
+        help = "listen on this unix socket for `workers=N`, `force_workers=N` and `per-task-mem=SIZE` lines"
+    )]
+    pub socket_file: Option<String>,
+
+    #[arg(
+        long,
+        action,
+        help = "print each task result to stdout as a JSON line once it is done"
+    )]
+    pub stream_results: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "log the time spent in each phase of the scheduler loop at the end"
+    )]
+    pub phase_timing: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "sample rss and cpu of every running task each tick into run_dir/cirno_profile.csv"
+    )]
+    pub profile: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "print the effective configuration as TOML and exit"
+    )]
+    pub print_config: bool,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "toml file with settings keyed like --print-config prints them, flags given here override it"
+    )]
+    pub config: Option<String>,
+}
+
+impl Args {
+    // like `Args::try_parse_from`, with the values of a `--config` file as defaults,
+    // so any flag given explicitly wins, a list given explicitly replaces the file's
+    pub fn parse_with_config<I, T>(argv: I) -> Result<Args, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+        let config = match find_config_arg(&argv) {
+            Some(config) => config,
+            None => return Args::try_parse_from(argv),
+        };
+        let fail = |message: String| Args::command().error(ErrorKind::InvalidValue, message);
+        let contents = std::fs::read_to_string(&config)
+            .map_err(|e| fail(format!("failed to read --config {}: {}", config, e)))?;
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|e| fail(format!("bad toml in --config {}: {}", config, e)))?;
+
+        let mut command = Args::command();
+        // an optional value toml can only turn on, see below
+        let mut shuffle_on = false;
+        for (key, value) in &table {
+            if key == "config" || !command.get_arguments().any(|arg| arg.get_id() == key) {
+                return Err(fail(format!(
+                    "unknown key `{}` in --config {}",
+                    key, config
+                )));
+            }
+            if key == "shuffle" && value.as_bool().is_some() {
+                shuffle_on = value.as_bool() == Some(true);
+                continue;
+            }
+            let values = toml_values(value)
+                .ok_or_else(|| fail(format!("bad value for `{}` in --config {}", key, config)))?;
+            command = command.mut_arg(key, |arg| arg.default_values(values).required(false));
+        }
+        let matches = command.try_get_matches_from(argv)?;
+        let mut args = Args::from_arg_matches(&matches)?;
+        if shuffle_on && args.shuffle.is_none() {
+            args.shuffle = Some(None);
+        }
+        Ok(args)
+    }
+
+    // reject combinations which would only misbehave at runtime
+    pub fn validate(&self) -> Result<(), String> {
+        if self.workers == 0 {
+            return Err("--workers must be at least 1".to_string());
+        }
+        if self.max_spawns_per_tick == 0 {
+            return Err("--max-spawns-per-tick must be at least 1".to_string());
+        }
+        if self.force_workers > self.workers {
+            return Err(format!(
+                "--force-workers ({}) is larger than --workers ({}), tasks would skip the monitor, lower it with -f",
+                self.force_workers, self.workers
+            ));
+        }
+        if let Some(min_workers) = self.min_workers {
+            if min_workers > self.workers {
+                return Err(format!(
+                    "--min-workers ({}) is larger than --workers ({})",
+                    min_workers, self.workers
+                ));
+            }
+        }
+        if !(self.tick_rate.is_finite() && self.tick_rate > 0.0) {
+            return Err(format!(
+                "--tick-rate ({}) must be larger than 0",
+                self.tick_rate
+            ));
+        }
+        for (name, value) in [
+            ("--high-mem-thres", self.high_mem_thres),
+            ("--low-mem-thres", self.low_mem_thres),
+        ] {
+            if !(value > 0.0 && value <= 1.0) {
+                return Err(format!("{} ({}) must be a ratio in (0, 1]", name, value));
+            }
+        }
+        if self.low_mem_thres > self.high_mem_thres {
+            return Err(format!(
+                "--low-mem-thres ({}) is larger than --high-mem-thres ({})",
+                self.low_mem_thres, self.high_mem_thres
+            ));
+        }
+        if self.gpus_per_task > 0 && !self.with_gpu {
+            return Err("--gpus-per-task needs --with-gpu".to_string());
+        }
+        if self.with_gpu && self.gpu_vendor == GpuVendor::Intel {
+            return Err("--gpu-vendor intel is not supported yet".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.gpu_mem_thres) {
+            return Err(format!(
+                "--gpu-mem-thres ({}) must be a ratio in [0, 1]",
+                self.gpu_mem_thres
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.watch_dir_thres) {
+            return Err(format!(
+                "--watch-dir-thres ({}) must be a ratio in [0, 1]",
+                self.watch_dir_thres
+            ));
+        }
+        if self.gpu_util_thres > 1.0 {
+            return Err(format!(
+                "--gpu-util-thres ({}) must be a ratio not larger than 1",
+                self.gpu_util_thres
+            ));
+        }
+        if self.disk_util_thres > 1.0 {
+            return Err(format!(
+                "--disk-util-thres ({}) must be a ratio not larger than 1",
+                self.disk_util_thres
+            ));
+        }
+        if !(self.load_avg_thres.is_finite() && self.load_avg_thres > 0.0) {
+            return Err(format!(
+                "--load-avg-thres ({}) must be larger than 0",
+                self.load_avg_thres
+            ));
+        }
+        for (name, value) in [
+            ("--timeout-wait", self.timeout_wait),
+            ("--mem-kill-grace", self.mem_kill_grace),
+            ("--min-run-before-kill", self.min_run_before_kill),
+            ("--min-healthy-runtime", self.min_healthy_runtime),
+            ("--drain-timeout", self.drain_timeout),
+        ] {
+            if !(value.is_finite() && value >= 0.0) {
+                return Err(format!("{} ({}) must not be negative", name, value));
+            }
+        }
+        if self.output_mode == OutputMode::Journal && self.idle_timeout >= 0.0 {
+            return Err(
+                "--idle-timeout watches the task log files, it does not work with --output-mode journal"
+                    .to_string(),
+            );
+        }
+        if self.watch_input && self.input_format != InputFormat::Line {
+            return Err("--watch-input only supports --input-format line".to_string());
+        }
+        if self.watch_input && self.input_list.len() > 1 {
+            return Err("--watch-input follows a single task list".to_string());
+        }
+        if self.watch_input && self.input_list[0] == "-" {
+            return Err("--watch-input follows a file, it can not read stdin".to_string());
+        }
+        if self.input_list.iter().filter(|input| *input == "-").count() > 1 {
+            return Err("stdin can only be read as one task list".to_string());
+        }
+        if let Some(self_nice) = self.self_nice {
+            if !(-20..=19).contains(&self_nice) {
+                return Err(format!("--self-nice ({}) must be in [-20, 19]", self_nice));
+            }
+        }
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                return Err(format!("--nice ({}) must be in [-20, 19]", nice));
+            }
+        }
+        if !self.priority_aging.is_finite() {
+            return Err(format!(
+                "--priority-aging ({}) must be a number",
+                self.priority_aging
+            ));
+        }
+        Ok(())
+    }
+
+    // render every setting except `print_config` itself as a toml document
+    pub fn to_toml(&self) -> String {
+        let mut lines = vec![
+            format!("input_list = {}", toml_str_array(&self.input_list)),
+            format!("workers = {}", self.workers),
+            format!("force_workers = {}", self.force_workers),
+            format!("ramp_up = {}", self.ramp_up),
+            format!("max_spawns_per_tick = {}", self.max_spawns_per_tick),
+            format!("timeout = {:?}", self.timeout),
+            format!("timeout_wait = {:?}", self.timeout_wait),
+            format!(
+                "timeout_signal = {}",
+                toml_str_array(
+                    &self
+                        .timeout_signal
+                        .iter()
+                        .map(|(signal, delay)| match delay {
+                            Some(delay) => format!("{}:{}", signal_name(*signal), delay),
+                            None => signal_name(*signal),
+                        })
+                        .collect::<Vec<_>>()
+                )
+            ),
+            format!("idle_timeout = {:?}", self.idle_timeout),
+            format!("drain_timeout = {:?}", self.drain_timeout),
+            format!("force_stop_retries = {}", self.force_stop_retries),
+            format!("max_retries = {}", self.max_retries),
+            format!("resume = {}", self.resume),
+            format!("tick_rate = {:?}", self.tick_rate),
+            format!("high_mem_thres = {:?}", self.high_mem_thres),
+            format!("low_mem_thres = {:?}", self.low_mem_thres),
+            format!("per_task_mem = {}", self.per_task_mem),
+            format!("reversed_mem = {}", self.reversed_mem),
+            format!(
+                "mem_kill_signal = {}",
+                toml_str(&signal_name(self.mem_kill_signal))
+            ),
+            format!("mem_kill_grace = {:?}", self.mem_kill_grace),
+            format!("min_run_before_kill = {:?}", self.min_run_before_kill),
+            format!("min_healthy_runtime = {:?}", self.min_healthy_runtime),
+            format!("kill_scope = {}", toml_enum(self.kill_scope)),
+            format!("load_avg_thres = {:?}", self.load_avg_thres),
+            format!("swap_thres = {:?}", self.swap_thres),
+            format!("priority_aging = {:?}", self.priority_aging),
+            format!("run_dir = {}", toml_str(&self.run_dir)),
+            format!("log_shard = {}", self.log_shard),
+            format!("output_mode = {}", toml_enum(self.output_mode)),
+            format!("report_order = {}", toml_enum(self.report_order)),
+            format!("report_format = {}", toml_enum(self.report_format)),
+            format!("with_gpu = {}", self.with_gpu),
+            format!("gpu_vendor = {}", toml_enum(self.gpu_vendor)),
+            format!("with_task_name = {}", self.with_task_name),
+            format!("name_first_token = {}", self.name_first_token),
+            format!("input_format = {}", toml_enum(self.input_format)),
+            format!("watch_input = {}", self.watch_input),
+            format!("daemon = {}", self.daemon),
+            format!("fail_on_empty = {}", self.fail_on_empty),
+            format!("dedup = {}", self.dedup),
+            format!("gpu_mem_thres = {:?}", self.gpu_mem_thres),
+            format!("gpu_util_thres = {:?}", self.gpu_util_thres),
+            format!("gpus_per_task = {}", self.gpus_per_task),
+            format!("cpu_affinity = {}", self.cpu_affinity),
+            format!("watch_dir = {}", toml_str_array(&self.watch_dir)),
+            format!("watch_dir_thres = {:?}", self.watch_dir_thres),
+            format!("disk_util_thres = {:?}", self.disk_util_thres),
+            format!("allow_commands = {}", toml_str_array(&self.allow_commands)),
+            format!("deny_commands = {}", toml_str_array(&self.deny_commands)),
+            format!("only = {}", toml_str_array(&self.only)),
+            format!("exclude = {}", toml_str_array(&self.exclude)),
+            format!("exit_code_mode = {}", toml_enum(self.exit_code_mode)),
+            format!("stream_results = {}", self.stream_results),
+            format!("phase_timing = {}", self.phase_timing),
+            format!("profile = {}", self.profile),
+        ];
+        // toml has no null, leave unset options out
+        if let Some(limit) = self.limit {
+            lines.push(format!("limit = {}", limit));
+        }
+        if let Some(min_workers) = self.min_workers {
+            lines.push(format!("min_workers = {}", min_workers));
+        }
+        match self.shuffle {
+            Some(Some(seed)) => lines.push(format!("shuffle = {}", seed)),
+            Some(None) => lines.push("shuffle = true".to_string()),
+            None => {}
+        }
+        if let Some(task_mem_limit) = self.task_mem_limit {
+            lines.push(format!("task_mem_limit = {}", task_mem_limit));
+        }
+        if let Some(tree_depth) = self.tree_depth {
+            lines.push(format!("tree_depth = {}", tree_depth));
+        }
+        if let Some(heavy_workers) = self.heavy_workers {
+            lines.push(format!("heavy_workers = {}", heavy_workers));
+        }
+        if let Some(task_meta) = &self.task_meta {
+            lines.push(format!("task_meta = {}", toml_str(task_meta)));
+        }
+        if let Some(self_nice) = self.self_nice {
+            lines.push(format!("self_nice = {}", self_nice));
+        }
+        if let Some(nice) = self.nice {
+            lines.push(format!("nice = {}", nice));
+        }
+        if let Some(ionice) = self.ionice {
+            lines.push(format!("ionice = {}", toml_str(&io_priority_name(ionice))));
+        }
+        if let Some(log_file) = &self.log_file {
+            lines.push(format!("log_file = {}", toml_str(log_file)));
+        }
+        if let Some(heartbeat_file) = &self.heartbeat_file {
+            lines.push(format!("heartbeat_file = {}", toml_str(heartbeat_file)));
+        }
+        if let Some(socket_file) = &self.socket_file {
+            lines.push(format!("socket_file = {}", toml_str(socket_file)));
+        }
+        if let Some(on_status_change) = &self.on_status_change {
+            lines.push(format!("on_status_change = {}", toml_str(on_status_change)));
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+// `auto` is kept as 0 and resolved by the monitor
+fn parse_per_task_mem(value: &str) -> Result<usize, String> {
+    if value == "auto" {
+        return Ok(0);
+    }
+    parse_mem_arg(value)
+}
+
+fn parse_mem_arg(value: &str) -> Result<usize, String> {
+    parse_mem_size(value).ok_or_else(|| format!("bad memory size `{}`, like `4G` or `512M`", value))
+}
+
+fn parse_io_priority_arg(value: &str) -> Result<i32, String> {
+    parse_io_priority(value).ok_or_else(|| {
+        format!(
+            "bad io priority `{}`, like `best-effort:7`, `idle` or `realtime:0`",
+            value
+        )
+    })
+}
+
+fn parse_timeout_step(value: &str) -> Result<(Signal, Option<f64>), String> {
+    let (signal, delay) = match value.split_once(':') {
+        Some((signal, delay)) => match delay.parse::<f64>() {
+            Ok(delay) if delay.is_finite() && delay >= 0.0 => (signal, Some(delay)),
+            _ => return Err(format!("bad delay `{}`, seconds like `10`", delay)),
+        },
+        None => (value, None),
+    };
+    Ok((parse_signal_arg(signal)?, delay))
+}
+
+fn parse_signal_arg(value: &str) -> Result<Signal, String> {
+    parse_signal(value).ok_or_else(|| format!("unknown signal `{}`", value))
+}
+
+// the value of `--config FILE` or `--config=FILE`, found before clap parses anything
+// since it decides the defaults
+fn find_config_arg(argv: &[OsString]) -> Option<String> {
+    let mut args = argv.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(|config| config.into_owned());
+        }
+        if let Some(config) = arg.strip_prefix("--config=") {
+            return Some(config.to_string());
+        }
+    }
+    None
+}
+
+// a toml value as clap would take it from the command line, arrays give a value each
+fn toml_values(value: &toml::Value) -> Option<Vec<String>> {
+    let scalar = |value: &toml::Value| match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    };
+    match value {
+        toml::Value::Array(values) => values.iter().map(scalar).collect(),
+        value => scalar(value).map(|value| vec![value]),
+    }
+}
+
+// quote a string as a toml basic string
+fn toml_str(value: &str) -> String {
+    let mut res = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04X}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn toml_str_array(values: &[String]) -> String {
+    let items: Vec<String> = values.iter().map(|v| toml_str(v)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn toml_enum<T: ValueEnum>(value: T) -> String {
+    // every variant has a possible value, none are skipped
+    toml_str(value.to_possible_value().unwrap().get_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // parse `args` with a `--config` file holding `config`
+    fn parse(name: &str, config: &str, args: &[&str]) -> Result<Args, clap::Error> {
+        let path = std::env::temp_dir().join(format!("cirno-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, config).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let argv = ["cirno", "--config", &path]
+            .into_iter()
+            .chain(args.iter().copied());
+        let res = Args::parse_with_config(argv);
+        let _ = std::fs::remove_file(&path);
+        res
+    }
+
+    #[test]
+    fn config_merges_with_cli() {
+        let config = "workers = 8\nforce_workers = 2\ntimeout = 30.0\nramp_up = true\ninput_list = [\"a.txt\"]\n";
+        let args = parse("merge", config, &["-w", "4"]).unwrap();
+        assert_eq!(args.workers, 4);
+        assert_eq!(args.force_workers, 2);
+        assert_eq!(args.timeout, 30.0);
+        assert!(args.ramp_up);
+        assert_eq!(args.input_list, ["a.txt"]);
+
+        let args = parse("merge-list", config, &["b.txt"]).unwrap();
+        assert_eq!(args.workers, 8);
+        assert_eq!(args.input_list, ["b.txt"]);
+    }
+
+    #[test]
+    fn cli_list_replaces_config_list() {
+        let config = "workers = 1\ndeny_commands = [\"rm\", \"dd\"]\ninput_list = [\"a.txt\"]\n";
+        let args = parse("list", config, &[]).unwrap();
+        assert_eq!(args.deny_commands, ["rm", "dd"]);
+
+        let args = parse("list-cli", config, &["--deny-commands", "mkfs,shred"]).unwrap();
+        assert_eq!(args.deny_commands, ["mkfs", "shred"]);
+    }
+
+    #[test]
+    fn config_shuffle() {
+        let config = "workers = 1\ninput_list = [\"a.txt\"]\n";
+        let args = parse("shuffle-unset", config, &[]).unwrap();
+        assert_eq!(args.shuffle, None);
+
+        let args = parse("shuffle-on", &format!("{}shuffle = true\n", config), &[]).unwrap();
+        assert_eq!(args.shuffle, Some(None));
+
+        let args = parse("shuffle-off", &format!("{}shuffle = false\n", config), &[]).unwrap();
+        assert_eq!(args.shuffle, None);
+
+        let args = parse("shuffle-seed", &format!("{}shuffle = 7\n", config), &[]).unwrap();
+        assert_eq!(args.shuffle, Some(Some(7)));
+
+        let args = parse(
+            "shuffle-cli",
+            &format!("{}shuffle = true\n", config),
+            &["--shuffle=3"],
+        )
+        .unwrap();
+        assert_eq!(args.shuffle, Some(Some(3)));
+    }
+
+    #[test]
+    fn config_rejects_unknown_key() {
+        let config = "workers = 1\ninput_list = [\"a.txt\"]\nworker = 2\n";
+        let e = parse("unknown", config, &[]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidValue);
+        assert!(e.to_string().contains("unknown key `worker`"));
+
+        let e = parse("nested", "config = \"other.toml\"\n", &[]).unwrap_err();
+        assert!(e.to_string().contains("unknown key `config`"));
+    }
 }
@@ -0,0 +1,62 @@
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// source of time for timeout and runtime decisions,
+// swap in `MockClock` to drive time by hand
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+}
+
+// wall clock time since the unix epoch, for reporting only since NTP may step
+// it backwards, zero if the clock is before the epoch
+pub fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// a clock standing still until `advance` is called
+#[derive(Debug)]
+pub struct MockClock {
+    base: Instant,
+    offset: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            base: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+}
@@ -1,77 +1,368 @@
+use std::io::{Error, ErrorKind, Result};
 use std::process::Command;
 
+use clap::ValueEnum;
+use log::warn;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum GpuVendor {
+    /// NVML or `nvidia-smi`
     Nvidia,
+    /// `rocm-smi`
     Amd,
+    /// not supported yet
     Intel,
 }
 
+impl GpuVendor {
+    // the variable limiting which cards a process sees
+    pub fn visible_devices_env(&self) -> &'static str {
+        match self {
+            GpuVendor::Nvidia => "CUDA_VISIBLE_DEVICES",
+            GpuVendor::Amd => "HIP_VISIBLE_DEVICES",
+            GpuVendor::Intel => "ZE_AFFINITY_MASK",
+        }
+    }
+}
+
 pub struct GpuInfo {
     pub index: u32,
     pub name: String,
     pub memory_total: f64,
     pub memory_used: f64,
     pub memory_free: f64,
+    // busy share of the last sample in 0..=1, None if the tool does not report it
+    pub utilization: Option<f64>,
 }
 
 impl GpuInfo {
-    pub fn get_gpus_info(vendor: GpuVendor) -> Vec<GpuInfo> {
+    // share of memory free in 0..=1, None if the card reports no total memory
+    pub fn free_ratio(&self) -> Option<f64> {
+        if self.memory_total.is_nan() || self.memory_total <= 0.0 {
+            return None;
+        }
+        Some((self.memory_free / self.memory_total).clamp(0.0, 1.0))
+    }
+
+    // an error if the tool could not be run or failed, bad lines are only skipped
+    pub fn get_gpus_info(vendor: GpuVendor) -> Result<Vec<GpuInfo>> {
         match vendor {
-            GpuVendor::Nvidia => Self::get_nvidia_gpus_info(),
-            _ => unimplemented!(),
+            GpuVendor::Nvidia => {
+                #[cfg(feature = "nvml")]
+                if let Some(cards) = Self::get_nvml_gpus_info() {
+                    return Ok(cards);
+                }
+                Self::get_nvidia_gpus_info()
+            }
+            GpuVendor::Amd => Self::get_amd_gpus_info(),
+            // rejected by `Args::validate`
+            GpuVendor::Intel => Err(Error::new(
+                ErrorKind::Unsupported,
+                "intel gpus are not supported",
+            )),
         }
     }
 
-    fn get_nvidia_gpus_info() -> Vec<GpuInfo> {
+    // memory in MiB like nvidia-smi, rocm-smi reports bytes
+    fn get_amd_gpus_info() -> Result<Vec<GpuInfo>> {
+        let mut rocm_smi = Command::new("rocm-smi");
+        rocm_smi.args(["--showmeminfo", "vram", "--csv"]);
+        let res_string = run_smi(rocm_smi)?;
+        Ok(Self::parse_rocm_csv(&res_string))
+    }
+
+    // `device,VRAM Total Memory (B),VRAM Total Used Memory (B)` and a line per card
+    // like `card0,68702699520,10960896`, banner lines around it are skipped
+    fn parse_rocm_csv(output: &str) -> Vec<GpuInfo> {
+        let mut lines = output
+            .lines()
+            .map(|line| line.trim())
+            .skip_while(|line| !line.starts_with("device"));
+        let header: Vec<&str> = match lines.next() {
+            Some(header) => header.split(',').collect(),
+            None => {
+                warn!("No memory info from rocm-smi");
+                return Vec::new();
+            }
+        };
+        let used_col = header.iter().position(|col| col.contains("Used"));
+        let total_col = header
+            .iter()
+            .position(|col| col.contains("Total") && !col.contains("Used"));
+        let (total_col, used_col) = match (total_col, used_col) {
+            (Some(total_col), Some(used_col)) => (total_col, used_col),
+            _ => {
+                warn!("Unknown rocm-smi columns: {}", header.join(","));
+                return Vec::new();
+            }
+        };
+
+        let mut cards = Vec::new();
+        for line in lines {
+            if !line.starts_with("card") {
+                continue;
+            }
+            let card = Self::parse_rocm_line(line, total_col, used_col);
+            match card {
+                Some(card) => cards.push(card),
+                None => warn!("Skipped bad info line from rocm-smi: {}", line),
+            }
+        }
+        cards
+    }
+
+    fn parse_rocm_line(line: &str, total_col: usize, used_col: usize) -> Option<GpuInfo> {
+        const MIB: f64 = (1 << 20) as f64;
+        let items: Vec<&str> = line.split(',').map(|item| item.trim()).collect();
+        let total = items.get(total_col)?.parse::<f64>().ok()? / MIB;
+        let used = items.get(used_col)?.parse::<f64>().ok()? / MIB;
+        Some(GpuInfo {
+            index: items[0].trim_start_matches("card").parse::<u32>().ok()?,
+            name: items[0].to_string(),
+            memory_total: total,
+            memory_used: used,
+            memory_free: total - used,
+            utilization: None,
+        })
+    }
+
+    fn get_nvidia_gpus_info() -> Result<Vec<GpuInfo>> {
         let mut nvidia_smi = Command::new("nvidia-smi");
         nvidia_smi
-            .arg("--query-gpu=index,gpu_name,memory.total,memory.free,memory.used")
+            .arg("--query-gpu=index,gpu_name,memory.total,memory.free,memory.used,utilization.gpu")
             .arg("--format=csv,noheader,nounits");
-        let output = nvidia_smi
-            .output()
-            .expect("failed to execute \"nvidia-smi\"")
-            .stdout;
-        let res_string = String::from_utf8(output).expect("bad output from nvidia-smi");
-        let res_string = res_string.trim();
-        let lines = res_string.split("\n");
+        let res_string = run_smi(nvidia_smi)?;
         let mut cards: Vec<GpuInfo> = Vec::new();
-        for card_info in lines {
-            let mut card_info_items = card_info.split(",");
-            let index: u32 = card_info_items
-                .next()
-                .unwrap()
-                .parse::<u32>()
-                .expect("bad info line for card");
-
-            let name = card_info_items.next().unwrap().trim();
-            let memory_total: f64 = card_info_items
-                .next()
-                .unwrap()
-                .trim()
-                .parse::<f64>()
-                .expect("bad info line for card");
-            let memory_free: f64 = card_info_items
-                .next()
-                .unwrap()
-                .trim()
-                .parse::<f64>()
-                .expect("bad info line for card");
-            let memory_used: f64 = card_info_items
-                .next()
-                .unwrap()
-                .trim()
-                .parse::<f64>()
-                .expect("bad info line for card");
+        for card_info in res_string.lines() {
+            let card_info = card_info.trim();
+            if card_info.is_empty() {
+                continue;
+            }
+            match Self::parse_nvidia_line(card_info) {
+                Some(card) => cards.push(card),
+                None => warn!("Skipped bad info line from nvidia-smi: {}", card_info),
+            }
+        }
+
+        Ok(cards)
+    }
+
+    // memory in MiB like nvidia-smi, None if NVML is not usable so
+    // nvidia-smi is tried instead
+    #[cfg(feature = "nvml")]
+    fn get_nvml_gpus_info() -> Option<Vec<GpuInfo>> {
+        use nvml_wrapper::Nvml;
+        use std::sync::OnceLock;
+
+        const MIB: f64 = (1 << 20) as f64;
+        // loading the library is the slow part, do it once
+        static NVML: OnceLock<Option<Nvml>> = OnceLock::new();
+        let nvml = NVML
+            .get_or_init(|| match Nvml::init() {
+                Ok(nvml) => Some(nvml),
+                Err(e) => {
+                    warn!("Failed to init NVML, fall back to nvidia-smi: {}", e);
+                    None
+                }
+            })
+            .as_ref()?;
 
+        let mut cards = Vec::new();
+        for index in 0..nvml.device_count().ok()? {
+            let device = match nvml.device_by_index(index) {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!("Skipped gpu {} from NVML: {}", index, e);
+                    continue;
+                }
+            };
+            let memory = match device.memory_info() {
+                Ok(memory) => memory,
+                Err(e) => {
+                    warn!("Skipped gpu {} from NVML: {}", index, e);
+                    continue;
+                }
+            };
             cards.push(GpuInfo {
                 index,
-                name: name.to_string(),
-                memory_total,
-                memory_used,
-                memory_free,
-            })
+                name: device.name().unwrap_or_default(),
+                memory_total: memory.total as f64 / MIB,
+                memory_used: memory.used as f64 / MIB,
+                memory_free: memory.free as f64 / MIB,
+                utilization: device
+                    .utilization_rates()
+                    .ok()
+                    .map(|rates| rates.gpu as f64 / 100.0),
+            });
         }
+        Some(cards)
+    }
 
-        cards
+    // one line of `index,gpu_name,memory.total,memory.free,memory.used,utilization.gpu`,
+    // utilization is `[N/A]` on some cards
+    // fields are separated by `, `, a bare `,` is part of a localized number
+    fn parse_nvidia_line(line: &str) -> Option<GpuInfo> {
+        let items: Vec<&str> = line.split(", ").map(|item| item.trim()).collect();
+        if items.len() != 6 {
+            return None;
+        }
+
+        Some(GpuInfo {
+            index: items[0].parse::<u32>().ok()?,
+            name: items[1].to_string(),
+            memory_total: parse_number(items[2])?,
+            memory_free: parse_number(items[3])?,
+            memory_used: parse_number(items[4])?,
+            utilization: parse_number(items[5]).map(|percent| percent / 100.0),
+        })
+    }
+}
+
+// stdout of a `*-smi` tool, which exits nonzero when the driver is not ready
+fn run_smi(mut command: Command) -> Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| Error::new(e.kind(), format!("failed to execute {}: {}", program, e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::other(format!(
+            "{} failed with {}: {}",
+            program,
+            output.status,
+            stderr.trim()
+        )));
+    }
+    String::from_utf8(output.stdout).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("bad output from {}", program),
+        )
+    })
+}
+
+// parse numbers like `16384`, `16 384` or `16.384,5`, whatever the locale,
+// values like `[Not Supported]` or `[N/A]` give None
+fn parse_number(value: &str) -> Option<f64> {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_digit() || *c == '.' || *c == ',')
+        .collect();
+    if !value.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // the last separator is the decimal one if it is followed by at most
+    // two digits, every other separator groups thousands
+    let (int_part, frac_part) = match value.rfind(['.', ',']) {
+        Some(pos) if value.len() - pos - 1 <= 2 => (&value[..pos], &value[pos + 1..]),
+        _ => (value.as_str(), ""),
+    };
+    let int_part: String = int_part.chars().filter(|c| c.is_ascii_digit()).collect();
+    format!("{}.{}0", int_part, frac_part).parse::<f64>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu(memory_total: f64, memory_free: f64) -> GpuInfo {
+        GpuInfo {
+            index: 0,
+            name: "card0".to_string(),
+            memory_total,
+            memory_used: memory_total - memory_free,
+            memory_free,
+            utilization: None,
+        }
+    }
+
+    #[test]
+    fn free_ratio_of_zero_total() {
+        assert_eq!(gpu(0.0, 0.0).free_ratio(), None);
+        assert_eq!(gpu(0.0, 512.0).free_ratio(), None);
+        assert_eq!(gpu(f64::NAN, 512.0).free_ratio(), None);
+        assert_eq!(gpu(-1.0, 0.0).free_ratio(), None);
+    }
+
+    #[test]
+    fn free_ratio_is_clamped() {
+        assert_eq!(gpu(1024.0, 256.0).free_ratio(), Some(0.25));
+        assert_eq!(gpu(1024.0, 2048.0).free_ratio(), Some(1.0));
+        assert_eq!(gpu(1024.0, -1.0).free_ratio(), Some(0.0));
+    }
+
+    #[test]
+    fn parse_nvidia_line_plain() {
+        let card =
+            GpuInfo::parse_nvidia_line("1, NVIDIA A100-SXM4-40GB, 40960, 39936, 1024, 37").unwrap();
+        assert_eq!(card.index, 1);
+        assert_eq!(card.name, "NVIDIA A100-SXM4-40GB");
+        assert_eq!(card.memory_total, 40960.0);
+        assert_eq!(card.memory_free, 39936.0);
+        assert_eq!(card.memory_used, 1024.0);
+        assert_eq!(card.utilization, Some(0.37));
+    }
+
+    #[test]
+    fn parse_nvidia_line_localized() {
+        let card =
+            GpuInfo::parse_nvidia_line("0, Tesla T4, 15.360,5, 14 336, 1.024,5, [N/A]").unwrap();
+        assert_eq!(card.index, 0);
+        assert_eq!(card.name, "Tesla T4");
+        assert_eq!(card.memory_total, 15360.5);
+        assert_eq!(card.memory_free, 14336.0);
+        assert_eq!(card.memory_used, 1024.5);
+        assert_eq!(card.utilization, None);
+    }
+
+    #[test]
+    fn parse_nvidia_line_bad() {
+        assert!(GpuInfo::parse_nvidia_line("0, Tesla T4, 15360, 14336").is_none());
+        assert!(GpuInfo::parse_nvidia_line("0, Tesla T4, [N/A], 14336, 1024, 5").is_none());
+        assert!(GpuInfo::parse_nvidia_line("x, Tesla T4, 15360, 14336, 1024, 5").is_none());
+    }
+
+    const ROCM_CSV: &str = "\
+======================= ROCm System Management Interface =======================
+device,VRAM Total Memory (B),VRAM Total Used Memory (B)
+card0,17163091968,1073741824
+card1,8589934592,0
+============================= End of ROCm SMI Log ==============================
+";
+
+    #[test]
+    fn parse_rocm_csv_reads_cards() {
+        let cards = GpuInfo::parse_rocm_csv(ROCM_CSV);
+        assert_eq!(cards.len(), 2);
+        assert_eq!(cards[0].index, 0);
+        assert_eq!(cards[0].name, "card0");
+        assert_eq!(cards[0].memory_total, 16368.0);
+        assert_eq!(cards[0].memory_used, 1024.0);
+        assert_eq!(cards[0].memory_free, 15344.0);
+        assert_eq!(cards[0].utilization, None);
+        assert_eq!(cards[1].index, 1);
+        assert_eq!(cards[1].memory_free, 8192.0);
+    }
+
+    #[test]
+    fn parse_rocm_csv_unknown_columns() {
+        let output = "device,GPU use (%)\ncard0,12\n";
+        assert!(GpuInfo::parse_rocm_csv(output).is_empty());
+        assert!(GpuInfo::parse_rocm_csv("").is_empty());
+    }
+
+    #[test]
+    fn parse_rocm_csv_skips_bad_lines() {
+        let output = "\
+device,VRAM Total Memory (B),VRAM Total Used Memory (B)
+card0,N/A,N/A
+cardX,8589934592,0
+card2,8589934592,4294967296
+";
+        let cards = GpuInfo::parse_rocm_csv(output);
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].index, 2);
+        assert_eq!(cards[0].memory_used, 4096.0);
     }
 }
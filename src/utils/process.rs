@@ -1,24 +1,162 @@
-use std::char;
 use std::collections::VecDeque;
 use std::fmt::Display;
-use std::fs::{read_dir, read_to_string};
 use std::io::{ErrorKind, Result};
 use std::num::NonZeroI32;
-use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::{
+    char,
+    fs::{read_dir, read_to_string},
+    path::Path,
+};
 
-use rustix::param::page_size;
+#[cfg(target_os = "linux")]
+use rustix::fs::{major, minor, stat, FileType};
+#[cfg(target_os = "linux")]
+use rustix::param::{clock_ticks_per_second, page_size};
 use rustix::process::{kill_process, Pid, Signal};
+#[cfg(target_os = "linux")]
+use rustix::process::{sched_getaffinity, sched_setaffinity, CpuSet};
+#[cfg(not(target_os = "linux"))]
+use sysinfo::{ProcessRefreshKind, ProcessStatus, ProcessesToUpdate, System, UpdateKind};
 
+#[cfg(target_os = "linux")]
 const PROC_DIR: &str = "/proc";
+// number of `read(2)`, as shown in /proc/<pid>/syscall
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const READ_SYSCALL: Option<u64> = Some(0);
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "aarch64", target_arch = "riscv64")
+))]
+const READ_SYSCALL: Option<u64> = Some(63);
+#[cfg(all(
+    target_os = "linux",
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))
+))]
+const READ_SYSCALL: Option<u64> = None;
+// `/dev/tty`, which opens the controlling terminal whatever it is
+#[cfg(target_os = "linux")]
+const TTY_ALIAS: (u32, u32) = (5, 0);
+
+// names accepted for signals, without the `SIG` prefix
+const SIGNAL_NAMES: [(&str, Signal); 20] = [
+    ("HUP", Signal::Hup),
+    ("INT", Signal::Int),
+    ("QUIT", Signal::Quit),
+    ("ABRT", Signal::Abort),
+    ("KILL", Signal::Kill),
+    ("USR1", Signal::Usr1),
+    ("USR2", Signal::Usr2),
+    ("PIPE", Signal::Pipe),
+    ("ALRM", Signal::Alarm),
+    ("TERM", Signal::Term),
+    ("CHLD", Signal::Child),
+    ("CONT", Signal::Cont),
+    ("STOP", Signal::Stop),
+    ("TSTP", Signal::Tstp),
+    ("TTIN", Signal::Ttin),
+    ("TTOU", Signal::Ttou),
+    ("XCPU", Signal::Xcpu),
+    ("XFSZ", Signal::Xfsz),
+    ("VTALRM", Signal::Vtalarm),
+    ("WINCH", Signal::Winch),
+];
+
+// parse `TERM`, `SIGTERM`, `term` or a signal number
+pub fn parse_signal(name: &str) -> Option<Signal> {
+    if let Ok(raw) = name.parse::<i32>() {
+        return Signal::from_raw(raw);
+    }
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNAL_NAMES
+        .iter()
+        .find(|(signal_name, _)| *signal_name == name)
+        .map(|(_, signal)| *signal)
+}
+
+pub fn signal_name(signal: Signal) -> String {
+    match SIGNAL_NAMES.iter().find(|(_, known)| *known == signal) {
+        Some((name, _)) => name.to_string(),
+        None => (signal as i32).to_string(),
+    }
+}
+
+// io scheduling classes by their `ionice` names
+const IO_CLASS_NAMES: [(&str, i32); 3] = [("realtime", 1), ("best-effort", 2), ("idle", 3)];
+// the kernel packs the class above a 13 bit level
+const IO_CLASS_SHIFT: i32 = 13;
+
+// parse `CLASS:LEVEL` like `best-effort:7`, `be:7` or `2:7` into an ioprio value,
+// the level is 0 (highest) to 7 and defaults to 4, as in the kernel, idle takes none
+pub fn parse_io_priority(value: &str) -> Option<i32> {
+    let (class, level) = match value.split_once(':') {
+        Some((class, level)) => (class, Some(level.parse::<i32>().ok()?)),
+        None => (value, None),
+    };
+    let class = match class.to_ascii_lowercase().as_str() {
+        "rt" => 1,
+        "be" => 2,
+        class => match class.parse::<i32>() {
+            Ok(class) => class,
+            Err(_) => IO_CLASS_NAMES.iter().find(|(name, _)| *name == class)?.1,
+        },
+    };
+    let level = match (class, level) {
+        (1 | 2, None) => 4,
+        (1 | 2, Some(level)) if (0..=7).contains(&level) => level,
+        (3, None | Some(0)) => 0,
+        _ => return None,
+    };
+    Some((class << IO_CLASS_SHIFT) | level)
+}
+
+pub fn io_priority_name(io_priority: i32) -> String {
+    let class = io_priority >> IO_CLASS_SHIFT;
+    let level = io_priority & ((1 << IO_CLASS_SHIFT) - 1);
+    match IO_CLASS_NAMES.iter().find(|(_, known)| *known == class) {
+        Some(("idle", _)) => "idle".to_string(),
+        Some((name, _)) => format!("{}:{}", name, level),
+        None => format!("{}:{}", class, level),
+    }
+}
+
+// `ioprio_set(IOPRIO_WHO_PROCESS, 0, ..)` for the calling process,
+// a raw syscall only, so it may run in `pre_exec`
+#[cfg(target_os = "linux")]
+pub fn set_io_priority(io_priority: i32) -> Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    // Safety: the syscall takes plain integers
+    let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io_priority) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// other systems have no io priorities, tasks keep the default one
+#[cfg(not(target_os = "linux"))]
+pub fn set_io_priority(_io_priority: i32) -> Result<()> {
+    Ok(())
+}
 
 #[derive(Clone, Debug)]
 pub struct Process {
     pid: Pid,
     ppid: Option<Pid>,
     comm: String,
+    state: char,
     create_time: usize,
 }
 
+// read from /proc directly, it is much cheaper than going through sysinfo
+#[cfg(target_os = "linux")]
 impl Process {
     pub fn new(pid: Pid) -> Result<Self> {
         let proc_path = format!("{}/{}/stat", PROC_DIR, pid.as_raw_nonzero());
@@ -40,11 +178,15 @@ impl Process {
             .skip_while(|&x| !char::is_alphanumeric(x))
             .collect::<String>();
         let proc_stat: Vec<&str> = proc_stat.split_ascii_whitespace().collect();
+        let state = proc_stat
+            .first()
+            .and_then(|state| state.chars().next())
+            .ok_or(ErrorKind::NotFound)?;
         let ppid = proc_stat
             .get(1)
             .ok_or(ErrorKind::NotFound)?
             .parse::<i32>()
-            .expect("Bad format in proc/pid/stat");
+            .map_err(|_| ErrorKind::InvalidData)?;
         // Safety: the ppid is came from proc/stat file,
         // so it must be positive
         let ppid = if ppid == 0 {
@@ -56,12 +198,13 @@ impl Process {
             .get(19)
             .ok_or(ErrorKind::NotFound)?
             .parse::<usize>()
-            .expect("Bad format in proc/pid/stat");
+            .map_err(|_| ErrorKind::InvalidData)?;
 
         Ok(Process {
             pid,
             ppid,
             comm: comm.to_string(),
+            state,
             create_time: proc_create_time,
         })
     }
@@ -78,15 +221,53 @@ impl Process {
             Ok(proc_statm) => proc_statm,
             Err(_) => return 0,
         };
-        let mut proc_statm = proc_statm.split_whitespace();
-        let _size = proc_statm.next().unwrap().parse::<usize>().unwrap();
         // use `page` as unit
-        let res_size = proc_statm.next().unwrap().parse::<usize>().unwrap();
+        let res_size = match proc_statm
+            .split_whitespace()
+            .nth(1)
+            .and_then(|res_size| res_size.parse::<usize>().ok())
+        {
+            Some(res_size) => res_size,
+            None => return 0,
+        };
 
         // use `Byte` as unit
         res_size * page_size()
     }
 
+    // user and system time used so far, and of the children it has reaped
+    pub fn cpu_time(&self, with_reaped: bool) -> Duration {
+        let pid: i32 = self.pid.as_raw_nonzero().get();
+        let proc_path = format!("{}/{}/stat", PROC_DIR, pid);
+        let proc_stat = match read_to_string(Path::new(&proc_path)) {
+            Ok(proc_stat) => proc_stat,
+            Err(_) => return Duration::ZERO,
+        };
+        let proc_stat = proc_stat
+            .chars()
+            .skip_while(|&x| x != ')')
+            .skip_while(|&x| !char::is_alphanumeric(x))
+            .collect::<String>();
+        // `utime`, `stime`, then `cutime` and `cstime`, in clock ticks
+        let ticks: u64 = proc_stat
+            .split_ascii_whitespace()
+            .skip(11)
+            .take(if with_reaped { 4 } else { 2 })
+            .filter_map(|ticks| ticks.parse::<u64>().ok())
+            .sum();
+        Duration::from_secs_f64(ticks as f64 / clock_ticks_per_second() as f64)
+    }
+
+    // exists and is not a zombie waiting to be reaped
+    pub fn is_alive(&self) -> bool {
+        match Process::new(self.pid) {
+            Ok(current) => {
+                current.create_time == self.create_time && !matches!(current.state, 'Z' | 'X')
+            }
+            Err(_) => false,
+        }
+    }
+
     pub fn is_exist(&self) -> bool {
         let pid: i32 = self.pid.as_raw_nonzero().get();
         let proc_path = format!("{}/{}/stat", PROC_DIR, pid);
@@ -103,15 +284,191 @@ impl Process {
         let proc_stat: Vec<&str> = proc_stat.split_ascii_whitespace().collect();
         let proc_create_time = proc_stat
             .get(19)
-            .unwrap()
-            .parse::<usize>()
-            .expect("Bad format in proc/pid/stat");
+            .and_then(|create_time| create_time.parse::<usize>().ok());
+
+        proc_create_time == Some(self.create_time)
+    }
+
+    // sleeping in `read(2)` on the terminal `tty`, given as `(major, minor)`
+    pub fn is_reading_tty(&self, tty: (u32, u32)) -> bool {
+        let read_syscall = match READ_SYSCALL {
+            Some(read_syscall) => read_syscall,
+            None => return false,
+        };
+        if self.state != 'S' {
+            return false;
+        }
+        let pid = self.pid.as_raw_nonzero();
+        let syscall = match read_to_string(format!("{}/{}/syscall", PROC_DIR, pid)) {
+            Ok(syscall) => syscall,
+            Err(_) => return false,
+        };
+        let mut fields = syscall.split_ascii_whitespace();
+        if fields.next().and_then(|nr| nr.parse::<u64>().ok()) != Some(read_syscall) {
+            return false;
+        }
+        let fd = match fields
+            .next()
+            .and_then(|fd| u64::from_str_radix(fd.trim_start_matches("0x"), 16).ok())
+        {
+            Some(fd) => fd,
+            None => return false,
+        };
+        match stat(format!("{}/{}/fd/{}", PROC_DIR, pid, fd)) {
+            Ok(fd_stat) => {
+                let dev = (major(fd_stat.st_rdev), minor(fd_stat.st_rdev));
+                FileType::from_raw_mode(fd_stat.st_mode) == FileType::CharacterDevice
+                    && (dev == tty || dev == TTY_ALIAS)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+// controlling terminal of cirno as `(major, minor)`, tasks inherit it
+#[cfg(target_os = "linux")]
+pub fn controlling_tty() -> Option<(u32, u32)> {
+    let proc_stat = read_to_string(format!("{}/self/stat", PROC_DIR)).ok()?;
+    let proc_stat = proc_stat.rsplit_once(')')?.1;
+    // state, ppid, pgrp, session, then tty_nr
+    let tty_nr = proc_stat
+        .split_ascii_whitespace()
+        .nth(4)?
+        .parse::<u32>()
+        .ok()?;
+    if tty_nr == 0 {
+        return None;
+    }
+    Some((
+        (tty_nr >> 8) & 0xfff,
+        (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00),
+    ))
+}
+
+fn online_cpus() -> usize {
+    std::thread::available_parallelism()
+        .map(|cores| cores.get())
+        .unwrap_or(1)
+}
 
-        // os fatal, panic is better
-        self.create_time == proc_create_time
+// cpus cirno may run on, as limited by taskset or cgroups
+#[cfg(target_os = "linux")]
+pub fn allowed_cpus() -> Vec<usize> {
+    match sched_getaffinity(None) {
+        Ok(cpu_set) => (0..CpuSet::MAX_CPU)
+            .filter(|cpu| cpu_set.is_set(*cpu))
+            .collect(),
+        Err(_) => (0..online_cpus()).collect(),
     }
 }
 
+// children started by the process afterwards inherit it
+#[cfg(target_os = "linux")]
+pub fn set_cpu_affinity(pid: Pid, cpus: &[usize]) -> Result<()> {
+    let mut cpu_set = CpuSet::new();
+    for cpu in cpus {
+        cpu_set.set(*cpu);
+    }
+    sched_setaffinity(Some(pid), &cpu_set)?;
+    Ok(())
+}
+
+// without /proc, like on BSD and macOS, ask sysinfo which uses the platform apis,
+// `create_time` is then the start time in seconds
+#[cfg(not(target_os = "linux"))]
+impl Process {
+    pub fn new(pid: Pid) -> Result<Self> {
+        let (process, _) = query_process(pid).ok_or(ErrorKind::NotFound)?;
+        Ok(process)
+    }
+
+    pub fn mem(&self) -> usize {
+        match query_process(self.pid) {
+            Some((current, mem)) if current.create_time == self.create_time => mem,
+            _ => 0,
+        }
+    }
+
+    // exists and is not a zombie waiting to be reaped
+    pub fn is_alive(&self) -> bool {
+        match query_process(self.pid) {
+            Some((current, _)) => {
+                current.create_time == self.create_time && !matches!(current.state, 'Z' | 'X')
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_exist(&self) -> bool {
+        query_process(self.pid).is_some_and(|(current, _)| current.create_time == self.create_time)
+    }
+
+    // sysinfo only offers a cpu usage between two refreshes, not the time used so far
+    pub fn cpu_time(&self, _with_reaped: bool) -> Duration {
+        Duration::ZERO
+    }
+
+    // the blocking syscall of a process is only visible in /proc
+    pub fn is_reading_tty(&self, _tty: (u32, u32)) -> bool {
+        false
+    }
+
+    fn from_sysinfo(process: &sysinfo::Process) -> Option<Self> {
+        let pid = Pid::from_raw(process.pid().as_u32() as i32)?;
+        let ppid = process
+            .parent()
+            .and_then(|ppid| Pid::from_raw(ppid.as_u32() as i32));
+        let state = match process.status() {
+            ProcessStatus::Zombie => 'Z',
+            ProcessStatus::Dead => 'X',
+            _ => 'R',
+        };
+        Some(Process {
+            pid,
+            ppid,
+            comm: process.name().to_string_lossy().into_owned(),
+            state,
+            create_time: process.start_time() as usize,
+        })
+    }
+}
+
+impl Process {
+    pub fn get_pid(&self) -> Pid {
+        self.pid
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn controlling_tty() -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn allowed_cpus() -> Vec<usize> {
+    (0..online_cpus()).collect()
+}
+
+// no affinity api everywhere, tasks then run on any cpu
+#[cfg(not(target_os = "linux"))]
+pub fn set_cpu_affinity(_pid: Pid, _cpus: &[usize]) -> Result<()> {
+    Ok(())
+}
+
+// the process and its memory in `Byte`
+#[cfg(not(target_os = "linux"))]
+fn query_process(pid: Pid) -> Option<(Process, usize)> {
+    let sys_pid = sysinfo::Pid::from_u32(pid.as_raw_nonzero().get() as u32);
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[sys_pid]),
+        ProcessRefreshKind::new().with_memory(),
+    );
+    let process = system.process(sys_pid)?;
+    let mem = process.memory() as usize;
+    Process::from_sysinfo(process).map(|process| (process, mem))
+}
+
 impl Display for Process {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let ppid = match self.ppid {
@@ -140,12 +497,13 @@ impl PartialEq for Process {
     }
 }
 
-pub fn get_sys_process_list() -> Vec<Process> {
+// processes which can not be read, like ones gone while walking, are skipped
+#[cfg(target_os = "linux")]
+pub fn get_sys_process_list() -> Result<Vec<Process>> {
     let mut process_list = Vec::new();
 
     let proc_dir = Path::new(PROC_DIR);
-    // on *nix os, the /proc/ is must exist;
-    let proc_dir = read_dir(proc_dir).unwrap();
+    let proc_dir = read_dir(proc_dir)?;
 
     // iter all pid dir
     for entry in proc_dir {
@@ -160,7 +518,10 @@ pub fn get_sys_process_list() -> Vec<Process> {
             None => continue,
         };
 
-        let filename = filename.to_os_string().into_string().unwrap();
+        let filename = match filename.to_str() {
+            Some(filename) => filename,
+            None => continue,
+        };
         // convert filename to pid and get process object
         match filename.parse::<i32>() {
             Ok(pid) => {
@@ -176,24 +537,81 @@ pub fn get_sys_process_list() -> Vec<Process> {
         }
     }
 
-    process_list
+    Ok(process_list)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_sys_process_list() -> Result<Vec<Process>> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(ProcessesToUpdate::All, ProcessRefreshKind::new());
+    Ok(system
+        .processes()
+        .values()
+        .filter_map(Process::from_sysinfo)
+        .collect())
+}
+
+// processes started with `key=value` in their environment, which survives
+// fork and reparenting, unless a process clears it on purpose
+#[cfg(target_os = "linux")]
+pub fn get_processes_with_env(key: &str, value: &str) -> Result<Vec<Process>> {
+    let entry = format!("{}={}", key, value);
+    let mut process_list = get_sys_process_list()?;
+    process_list.retain(|process| {
+        let environ_path = format!("{}/{}/environ", PROC_DIR, process.pid.as_raw_nonzero());
+        match std::fs::read(environ_path) {
+            Ok(environ) => environ
+                .split(|byte| *byte == 0)
+                .any(|var| var == entry.as_bytes()),
+            Err(_) => false,
+        }
+    });
+    Ok(process_list)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_processes_with_env(key: &str, value: &str) -> Result<Vec<Process>> {
+    let entry = format!("{}={}", key, value);
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        ProcessRefreshKind::new().with_environ(UpdateKind::Always),
+    );
+    Ok(system
+        .processes()
+        .values()
+        .filter(|process| process.environ().iter().any(|var| *var == *entry))
+        .filter_map(Process::from_sysinfo)
+        .collect())
 }
 
 pub fn get_process_tree(pid: Pid, with_root: bool) -> Result<Vec<Process>> {
+    get_process_tree_with_depth(pid, with_root, None)
+}
+
+// like `get_process_tree`, but descendants deeper than `max_depth` are left out,
+// the root is at depth 0
+pub fn get_process_tree_with_depth(
+    pid: Pid,
+    with_root: bool,
+    max_depth: Option<usize>,
+) -> Result<Vec<Process>> {
     let mut childern_process_list: VecDeque<Process> = VecDeque::new();
-    let mut children: Vec<Process> = Vec::new();
-    let process_list = get_sys_process_list();
+    let mut children: Vec<(Process, usize)> = Vec::new();
+    let process_list = get_sys_process_list()?;
 
     // push first process(self) to stack
     let first_one = Process::new(pid)?;
-    children.push(first_one);
-    while let Some(child) = children.pop() {
+    children.push((first_one, 0));
+    while let Some((child, depth)) = children.pop() {
         // iter process_list to find children
-        for process in process_list.iter() {
-            if let Some(ppid) = process.ppid {
-                if ppid == child.pid {
-                    // this one is a child
-                    children.push(process.clone());
+        if max_depth.is_none_or(|max_depth| depth < max_depth) {
+            for process in process_list.iter() {
+                if let Some(ppid) = process.ppid {
+                    if ppid == child.pid {
+                        // this one is a child
+                        children.push((process.clone(), depth + 1));
+                    }
                 }
             }
         }
@@ -220,3 +638,52 @@ pub fn kill_process_tree(pid: Pid, signal: Signal, with_root: bool) -> Result<bo
 
     Ok(true)
 }
+
+// kill a process tree and make sure nothing survives, return false if
+// some process is still alive after `retries` rounds
+pub fn force_kill_process_tree(pid: Pid, with_root: bool, retries: usize) -> Result<bool> {
+    // freeze the tree first, so no child can be forked and escape while killing,
+    // re-walk until no new process shows up
+    let mut frozen: Vec<Process> = Vec::new();
+    for _ in 0..retries {
+        let mut has_new = false;
+        for process in get_process_tree(pid, with_root)? {
+            if frozen.contains(&process) {
+                continue;
+            }
+            let _ = kill_process(process.pid, Signal::Stop);
+            frozen.push(process);
+            has_new = true;
+        }
+        if !has_new {
+            break;
+        }
+    }
+
+    // kill and confirm, the killed ones may be reparented,
+    // so check both the frozen set and a fresh walk
+    for _ in 0..retries {
+        let mut alive: Vec<Process> = frozen
+            .iter()
+            .filter(|process| process.is_alive())
+            .cloned()
+            .collect();
+        if let Ok(process_list) = get_process_tree(pid, with_root) {
+            for process in process_list {
+                if process.is_alive() && !alive.contains(&process) {
+                    alive.push(process);
+                }
+            }
+        }
+        if alive.is_empty() {
+            return Ok(true);
+        }
+        for process in &alive {
+            let _ = kill_process(process.pid, Signal::Kill);
+        }
+        frozen = alive;
+        sleep(Duration::from_millis(10));
+    }
+
+    Ok(false)
+}
@@ -0,0 +1,176 @@
+// split a command line into words like a posix shell does, without expanding anything,
+// `'...'` keeps everything, `"..."` only lets `\` escape `"`, `\`, `$` and `` ` ``,
+// a bare `\` escapes any char, `None` if a quote is left open or the line ends in `\`
+pub fn split_command(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // a quoted empty string is still a word
+    let mut in_word = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            '\n' => {}
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next()? {
+                    '\n' => {}
+                    c => word.push(c),
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
+
+// `line` cut at each `sep` outside of quotes, quoting the same way as `split_command`
+pub fn split_unquoted(line: &str, sep: char) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (pos, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some('"'), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if c == sep => {
+                fields.push(&line[start..pos]);
+                start = pos + c.len_utf8();
+            }
+            (None, _) => {}
+        }
+    }
+    fields.push(&line[start..]);
+    fields
+}
+
+// `word` as `split_command` reads it back, single quoted if needed
+pub fn quote_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && !word
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '\'' | '"' | '\\'));
+    if plain {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+// tokens a shell would treat as operators, they reach the program as plain arguments
+pub fn is_shell_operator(word: &str) -> bool {
+    matches!(
+        word,
+        "|" | "||" | "&" | "&&" | ";" | "<" | ">" | ">>" | "2>" | "2>&1" | "&>"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Vec<String> {
+        split_command(line).unwrap()
+    }
+
+    #[test]
+    fn split_quoted_args() {
+        assert_eq!(split("echo 'a b' \"c d\""), ["echo", "a b", "c d"]);
+        assert_eq!(split("echo '' \"\""), ["echo", "", ""]);
+        assert_eq!(split("echo a'b c'd"), ["echo", "ab cd"]);
+        assert_eq!(split("echo '$HOME' \"a|b\""), ["echo", "$HOME", "a|b"]);
+    }
+
+    #[test]
+    fn split_embedded_spaces() {
+        assert_eq!(split("  echo \t a\\ b  "), ["echo", "a b"]);
+        assert_eq!(split("a\\\nb"), ["ab"]);
+        assert!(split(" \t ").is_empty());
+    }
+
+    #[test]
+    fn split_escaped_quotes() {
+        assert_eq!(
+            split(r#"echo "say \"hi\"" \'x"#),
+            ["echo", r#"say "hi""#, "'x"]
+        );
+        assert_eq!(split(r#"echo "a\b" 'a\b'"#), ["echo", r"a\b", r"a\b"]);
+        assert_eq!(split(r"echo 'it'\''s'"), ["echo", "it's"]);
+    }
+
+    #[test]
+    fn split_unbalanced_quotes() {
+        assert_eq!(split_command("echo 'a b"), None);
+        assert_eq!(split_command("echo \"a b"), None);
+        assert_eq!(split_command("echo \"a\\\""), None);
+        assert_eq!(split_command("echo a\\"), None);
+    }
+
+    #[test]
+    fn quote_word_round_trip() {
+        for word in [
+            "plain",
+            "",
+            "a b",
+            "it's",
+            r#"say "hi""#,
+            r"a\b",
+            "tab\there",
+        ] {
+            assert_eq!(split(&quote_word(word)), [word]);
+        }
+        assert_eq!(quote_word("plain"), "plain");
+    }
+
+    #[test]
+    fn split_unquoted_commas() {
+        assert_eq!(split_unquoted("a,b", ','), ["a", "b"]);
+        assert_eq!(
+            split_unquoted("n,echo 'a,b' \"c,d\",e", ','),
+            ["n", "echo 'a,b' \"c,d\"", "e"]
+        );
+        assert_eq!(split_unquoted(r"echo a\,b", ','), [r"echo a\,b"]);
+        assert_eq!(split_unquoted("echo 'a,b", ','), ["echo 'a,b"]);
+    }
+}
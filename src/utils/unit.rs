@@ -0,0 +1,84 @@
+// parse a memory size like `8G`, `512M`, `2.5GiB` or `4096`, `Byte` as result,
+// every unit is binary, so `1K`, `1KB` and `1KiB` are all 1024 bytes
+pub fn parse_mem_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let split_at = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split_at);
+    let number = number.parse::<f64>().ok()?;
+
+    let scale: usize = match unit.trim_start().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return None,
+    };
+
+    Some((number * scale as f64) as usize)
+}
+
+// the other way round, rounded up so it is never below `bytes`, like `1.5G`
+pub fn format_mem_size(bytes: usize) -> String {
+    let units = [
+        ("T", 1usize << 40),
+        ("G", 1 << 30),
+        ("M", 1 << 20),
+        ("K", 1 << 10),
+    ];
+    for (unit, scale) in units {
+        if bytes >= scale {
+            let number = (bytes as f64 / scale as f64 * 10.0).ceil() / 10.0;
+            return format!("{}{}", number, unit);
+        }
+    }
+    bytes.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_suffixes() {
+        assert_eq!(parse_mem_size("4G"), Some(4 << 30));
+        assert_eq!(parse_mem_size("512M"), Some(512 << 20));
+        assert_eq!(parse_mem_size("2.5GiB"), Some(5 << 29));
+        assert_eq!(parse_mem_size("1KB"), Some(1024));
+        assert_eq!(parse_mem_size(" 8 mb "), Some(8 << 20));
+        assert_eq!(parse_mem_size("4096"), Some(4096));
+    }
+
+    #[test]
+    fn parse_rejects() {
+        for value in ["-1G", "1e9", "G", "1X", "", "1.2.3M"] {
+            assert_eq!(parse_mem_size(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let sizes = [
+            0,
+            1023,
+            1024,
+            1025,
+            1536 << 10,
+            (1 << 30) - 1,
+            1 << 30,
+            (1 << 30) + 1,
+            3 * (1 << 30) / 7,
+            (5 << 40) + 12345,
+        ];
+        for bytes in sizes {
+            let formatted = format_mem_size(bytes);
+            let parsed = parse_mem_size(&formatted).unwrap();
+            // rounded up, so a limit given back is never tighter than the one set
+            assert!(parsed >= bytes, "{} -> {} -> {}", bytes, formatted, parsed);
+        }
+        assert_eq!(format_mem_size(1536 << 20), "1.5G");
+        assert_eq!(format_mem_size(1025), "1.1K");
+    }
+}